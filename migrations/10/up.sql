@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS login_throttles (
+    id INTEGER PRIMARY KEY,
+    kind VARCHAR NOT NULL,
+    key VARCHAR NOT NULL,
+    failures INTEGER NOT NULL,
+    last_failure TIMESTAMP NOT NULL,
+    locked_until TIMESTAMP,
+    unique (kind, key)
+);
+
+CREATE TABLE IF NOT EXISTS auth_events (
+    id INTEGER PRIMARY KEY,
+    created TIMESTAMP NOT NULL,
+    event VARCHAR NOT NULL,
+    username VARCHAR NOT NULL,
+    ip VARCHAR NOT NULL,
+    source VARCHAR NOT NULL
+);
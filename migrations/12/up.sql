@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS user_sessions (
+    id VARCHAR PRIMARY KEY,
+    user INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    created TIMESTAMP NOT NULL,
+    last_seen TIMESTAMP NOT NULL,
+    user_agent VARCHAR NOT NULL,
+    ip VARCHAR NOT NULL
+);
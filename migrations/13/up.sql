@@ -0,0 +1,2 @@
+ALTER TABLE user_sessions ADD COLUMN expires TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+UPDATE user_sessions SET expires = datetime(last_seen, '+30 days');
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN ldap_dn VARCHAR;
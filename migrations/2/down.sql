@@ -0,0 +1,2 @@
+DROP TABLE org_members;
+ALTER TABLE users DROP COLUMN is_org;
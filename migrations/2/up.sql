@@ -0,0 +1,9 @@
+ALTER TABLE users ADD COLUMN is_org BOOLEAN NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS org_members (
+    id INTEGER PRIMARY KEY,
+    org INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    member INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    is_owner BOOLEAN NOT NULL DEFAULT 0,
+    unique (org, member)
+);
@@ -0,0 +1,4 @@
+DROP TABLE collaborators;
+DROP TABLE team_repos;
+DROP TABLE team_members;
+DROP TABLE teams;
@@ -0,0 +1,29 @@
+CREATE TABLE IF NOT EXISTS teams (
+    id INTEGER PRIMARY KEY,
+    org INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    access INTEGER NOT NULL,
+    unique (org, name)
+);
+
+CREATE TABLE IF NOT EXISTS team_members (
+    id INTEGER PRIMARY KEY,
+    team INTEGER REFERENCES teams (id) ON DELETE CASCADE,
+    member INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    unique (team, member)
+);
+
+CREATE TABLE IF NOT EXISTS team_repos (
+    id INTEGER PRIMARY KEY,
+    team INTEGER REFERENCES teams (id) ON DELETE CASCADE,
+    repo INTEGER REFERENCES repos (id) ON DELETE CASCADE,
+    unique (team, repo)
+);
+
+CREATE TABLE IF NOT EXISTS collaborators (
+    id INTEGER PRIMARY KEY,
+    repo INTEGER REFERENCES repos (id) ON DELETE CASCADE,
+    member INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    access INTEGER NOT NULL,
+    unique (repo, member)
+);
@@ -0,0 +1,2 @@
+DROP TABLE protected_branch_pushers;
+DROP TABLE protected_branches;
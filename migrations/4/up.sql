@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS protected_branches (
+    id INTEGER PRIMARY KEY,
+    repo INTEGER REFERENCES repos (id) ON DELETE CASCADE,
+    pattern VARCHAR NOT NULL,
+    no_force_push BOOLEAN NOT NULL,
+    no_deletion BOOLEAN NOT NULL,
+    unique (repo, pattern)
+);
+
+CREATE TABLE IF NOT EXISTS protected_branch_pushers (
+    id INTEGER PRIMARY KEY,
+    rule INTEGER REFERENCES protected_branches (id) ON DELETE CASCADE,
+    member INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    team INTEGER REFERENCES teams (id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+ALTER TABLE protected_branches DROP COLUMN require_signed;
+DROP TABLE signing_keys;
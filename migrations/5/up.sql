@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS signing_keys (
+    id INTEGER PRIMARY KEY,
+    owner INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    kind VARCHAR NOT NULL,
+    fingerprint VARCHAR NOT NULL,
+    content TEXT NOT NULL
+);
+
+ALTER TABLE protected_branches ADD COLUMN require_signed BOOLEAN NOT NULL DEFAULT 0;
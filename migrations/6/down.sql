@@ -0,0 +1,2 @@
+DROP TABLE site_settings;
+ALTER TABLE users DROP COLUMN disabled;
@@ -0,0 +1,6 @@
+ALTER TABLE users ADD COLUMN disabled BOOLEAN NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS site_settings (
+    name VARCHAR PRIMARY KEY,
+    value VARCHAR NOT NULL
+);
@@ -0,0 +1,3 @@
+ALTER TABLE repos ADD COLUMN private BOOLEAN NOT NULL DEFAULT 0;
+UPDATE repos SET private = visibility != 'public';
+ALTER TABLE repos DROP COLUMN visibility;
@@ -0,0 +1,3 @@
+ALTER TABLE repos ADD COLUMN visibility VARCHAR NOT NULL DEFAULT 'public';
+UPDATE repos SET visibility = 'private' WHERE private;
+ALTER TABLE repos DROP COLUMN private;
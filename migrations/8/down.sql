@@ -0,0 +1,2 @@
+DROP TABLE repo_redirects;
+DROP TABLE repo_transfers;
@@ -0,0 +1,13 @@
+CREATE TABLE IF NOT EXISTS repo_transfers (
+    id INTEGER PRIMARY KEY,
+    repo INTEGER NOT NULL UNIQUE REFERENCES repos (id) ON DELETE CASCADE,
+    new_owner INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS repo_redirects (
+    owner INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name VARCHAR NOT NULL,
+    repo INTEGER NOT NULL REFERENCES repos (id) ON DELETE CASCADE,
+    unique (owner, name)
+);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS invites (
+    id INTEGER PRIMARY KEY,
+    code VARCHAR NOT NULL UNIQUE,
+    created_by INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    expires TIMESTAMP NOT NULL,
+    used_by INTEGER REFERENCES users (id) ON DELETE CASCADE
+);
@@ -0,0 +1,177 @@
+use {db, Config, Error, Result};
+use git::signing::Verifier;
+use types::BranchRule;
+
+use clap::ArgMatches;
+use git2::{self, Oid, Repository};
+use r2d2;
+use r2d2_sqlite::SqliteConnectionManager;
+
+use std::{env, process};
+use std::io::{self, BufRead};
+
+pub fn run(config: Config, matches: &ArgMatches) {
+    // The push is only rejected once _run has returned, so that the
+    // verifier's temporary keyrings are cleaned up first
+    match _run(config, matches) {
+        Ok(None) => (),
+        Ok(Some(reason)) => fail(&reason),
+        Err(e) => fail(&format!("Internal error: {}", e)),
+    }
+}
+
+/// Runs the hook, returning why the push was rejected if it was.
+fn _run(config: Config, matches: &ArgMatches) -> Result<Option<String>> {
+    let hook = matches.get_one::<String>("HOOK").expect("Missing HOOK argument");
+    if hook != "pre-receive" {
+        return Ok(None);
+    }
+
+    // Create db connection pool
+    let manager = SqliteConnectionManager::file(config.db_path);
+    let pool = r2d2::Pool::new(manager).expect("Failed to create pool");
+
+    let user = match matches.get_one::<String>("KEYID") {
+        Some(id) => pusher(&pool, id)?,
+        None => None,
+    };
+
+    // Hooks are run from inside the bare repository at {repo_dir}/{owner}/{repo}.git
+    let path = env::current_dir()?;
+    let reponame = path.file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("")
+        .trim_end_matches(".git")
+        .to_string();
+    let owner = path.parent()
+        .and_then(|p| p.file_name())
+        .and_then(|n| n.to_str())
+        .unwrap_or("")
+        .to_string();
+    let repo_id = if let Some(id) = db::read::repo_id(&pool, &owner, &reponame)? {
+        id
+    } else {
+        return Ok(Some(String::from("Repository does not exist")));
+    };
+
+    let rules = db::read::branch_rules(&pool, repo_id)?;
+    // Pushed objects are quarantined until the hook succeeds, git passes their
+    // location through the environment.
+    let repo = Repository::open_from_env()?;
+    let mut verifier = None;
+
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() != 3 {
+            continue;
+        }
+
+        let old = Oid::from_str(parts[0])?;
+        let new = Oid::from_str(parts[1])?;
+        let branch = if parts[2].starts_with("refs/heads/") {
+            &parts[2][11..]
+        } else {
+            continue;
+        };
+
+        for rule in rules.iter().filter(|r| r.matches(branch)) {
+            if let Some(reason) = check_rule(&pool, &repo, rule, user, branch, old, new)? {
+                return Ok(Some(reason));
+            }
+
+            if rule.require_signed && !new.is_zero() {
+                if verifier.is_none() {
+                    let keys = db::read::signing_keys(&pool, None)?;
+                    verifier = Some(Verifier::new(&keys)?);
+                }
+                let verifier = verifier.as_ref().unwrap();
+                if let Some(reason) = check_signatures(&repo, verifier, branch, old, new)? {
+                    return Ok(Some(reason));
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn check_rule(pool: &db::Pool,
+              repo: &Repository,
+              rule: &BranchRule,
+              user: Option<i32>,
+              branch: &str,
+              old: Oid,
+              new: Oid)
+    -> Result<Option<String>>
+{
+    if rule.restricted() {
+        let allowed = match user {
+            Some(user) => db::read::branch_rule_allows(pool, rule.id, user)?,
+            None => false,
+        };
+        if !allowed {
+            return Ok(Some(format!("You are not allowed to push to the protected branch {}", branch)));
+        }
+    }
+
+    if new.is_zero() {
+        if rule.no_deletion {
+            return Ok(Some(format!("The protected branch {} can not be deleted", branch)));
+        }
+    } else if !old.is_zero() && rule.no_force_push && old != new &&
+        !repo.graph_descendant_of(new, old)?
+    {
+        return Ok(Some(format!("Force pushing to the protected branch {} is not allowed", branch)));
+    }
+    Ok(None)
+}
+
+/// Checks that every commit introduced by this push is signed by a key
+/// registered with Valentine.
+fn check_signatures(repo: &Repository, verifier: &Verifier, branch: &str, old: Oid, new: Oid)
+    -> Result<Option<String>>
+{
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(new)?;
+    if !old.is_zero() {
+        revwalk.hide(old)?;
+    }
+    // Commits reachable from an existing ref have already been accepted
+    revwalk.hide_glob("*")?;
+
+    for id in revwalk {
+        let id = id?;
+        match repo.extract_signature(&id, None) {
+            Ok((signature, data)) => if !verifier.verify(&signature, &data)? {
+                return Ok(Some(format!("Commit {} on the protected branch {} is not signed by a key registered with Valentine",
+                                       id, branch)));
+            },
+            Err(ref e) if e.code() == git2::ErrorCode::NotFound => {
+                return Ok(Some(format!("Commit {} on the protected branch {} is not signed", id, branch)));
+            }
+            Err(e) => return Err(Error::from(e)),
+        }
+    }
+    Ok(None)
+}
+
+/// Resolves the `key-{id}` or `user-{id}` passed along by the ssh and http
+/// transports to the id of the user that is pushing.
+fn pusher(pool: &db::Pool, id: &str) -> Result<Option<i32>> {
+    if id.starts_with("key-") {
+        match id[4..].parse::<i32>() {
+            Ok(id) => db::read::user_by_key_id(pool, id),
+            Err(_) => Ok(None),
+        }
+    } else if id.starts_with("user-") {
+        Ok(id[5..].parse::<i32>().ok())
+    } else {
+        Ok(None)
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("Valentine: {}", msg);
+    process::exit(1);
+}
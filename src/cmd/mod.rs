@@ -1,3 +1,4 @@
 pub mod backup;
+pub mod hook;
 pub mod ssh;
 pub mod web;
@@ -1,5 +1,6 @@
 use {db, Config, Result};
 use git::AccessMode;
+use types::Access;
 
 use clap::ArgMatches;
 use r2d2;
@@ -32,8 +33,9 @@ fn _run(config: Config, matches: &ArgMatches) -> Result<()> {
         fail("Invalid repository path", None);
     }
 
-    let username = rr[0];
-    let reponame = rr[1].trim_end_matches(".git");
+    let mut username = rr[0].to_string();
+    let mut reponame = rr[1].trim_end_matches(".git").to_string();
+    let mut repo_path = repo_path.to_string();
 
     let requested_mode = if let Some(mode) = AccessMode::new(&verb) {
         mode
@@ -45,33 +47,44 @@ fn _run(config: Config, matches: &ArgMatches) -> Result<()> {
     let manager = SqliteConnectionManager::file(config.db_path);
     let pool = r2d2::Pool::new(manager).expect("Failed to create pool");
 
-    if !db::read::user_exists(&pool, username)? {
+    if !db::read::user_exists(&pool, &username)? {
         fail("Repository owner does not exist", None);
     }
 
-    if !db::read::repo_exists(&pool, username, reponame)? {
-        fail("Repository does not exist or you do not have access", None);
+    // Transferred repos are served from their new location
+    if let Some((owner, name)) = db::read::repo_redirect(&pool, &username, &reponame)? {
+        repo_path = format!("{}/{}.git", owner, name);
+        username = owner;
+        reponame = name;
     }
 
-    let private = db::read::repo_is_private(&pool, username, reponame)?;
+    if !db::read::repo_exists(&pool, &username, &reponame)? {
+        fail("Repository does not exist or you do not have access", None);
+    }
 
-    if requested_mode == AccessMode::Write || private {
-        let user = if let Some(user) = db::read::user_by_key_id(&pool, key_id)? {
-            user
-        } else {
-            fail("Internal error", None);
-        };
+    let user = if let Some(user) = db::read::user_by_key_id(&pool, key_id)? {
+        user
+    } else {
+        fail("Internal error", None);
+    };
 
-        // TODO: We want to check if a user has *access* rather than if the user
-        // owns the repo, whenever we allow access to multiple users
-        if !db::read::user_owns_repo(&pool, user, reponame)? {
-            fail("Repository does not exist or you do not have access", None);
-        };
+    if db::read::user_disabled(&pool, user)?.unwrap_or(true) {
+        fail("Your account has been disabled", None);
     }
 
+    let required = if requested_mode == AccessMode::Write {
+        Access::Write
+    } else {
+        Access::Read
+    };
+    if db::read::access(&pool, Some(user), &username, &reponame)? < required {
+        fail("Repository does not exist or you do not have access", None);
+    };
+
     let command = process::Command::new(verb)
-        .arg(repo_path)
+        .arg(&repo_path)
         .current_dir(config.repo_dir)
+        .env("VALENTINE_KEY_ID", format!("key-{}", key_id))
         .status();
     if let Ok(status) = command {
         if !status.success() {
@@ -82,7 +95,7 @@ fn _run(config: Config, matches: &ArgMatches) -> Result<()> {
     }
 
     if requested_mode == AccessMode::Write {
-        db::update::repo(&pool, username, reponame)?;
+        db::update::repo(&pool, &username, &reponame)?;
     }
     Ok(())
 }
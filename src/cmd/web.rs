@@ -1,4 +1,4 @@
-use {Config, Context};
+use {db, git, highlight, ldap, Config, Context};
 use routes::*;
 
 use hayaku::{Http, Router};
@@ -6,8 +6,12 @@ use r2d2;
 use r2d2_sqlite::SqliteConnectionManager;
 
 use std::{env, fs, process};
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::thread;
+use std::time::Duration;
 
 pub fn run(config: Config, config_path: PathBuf) {
     info!("Starting up server");
@@ -22,6 +26,34 @@ pub fn run(config: Config, config_path: PathBuf) {
         let migrations = Migrations::new(vec![
             M::up(include_str!("../../migrations/1/up.sql"))
                 .down(include_str!("../../migrations/1/down.sql")),
+            M::up(include_str!("../../migrations/2/up.sql"))
+                .down(include_str!("../../migrations/2/down.sql")),
+            M::up(include_str!("../../migrations/3/up.sql"))
+                .down(include_str!("../../migrations/3/down.sql")),
+            M::up(include_str!("../../migrations/4/up.sql"))
+                .down(include_str!("../../migrations/4/down.sql")),
+            M::up(include_str!("../../migrations/5/up.sql"))
+                .down(include_str!("../../migrations/5/down.sql")),
+            M::up(include_str!("../../migrations/6/up.sql"))
+                .down(include_str!("../../migrations/6/down.sql")),
+            M::up(include_str!("../../migrations/7/up.sql"))
+                .down(include_str!("../../migrations/7/down.sql")),
+            M::up(include_str!("../../migrations/8/up.sql"))
+                .down(include_str!("../../migrations/8/down.sql")),
+            M::up(include_str!("../../migrations/9/up.sql"))
+                .down(include_str!("../../migrations/9/down.sql")),
+            M::up(include_str!("../../migrations/10/up.sql"))
+                .down(include_str!("../../migrations/10/down.sql")),
+            M::up(include_str!("../../migrations/11/up.sql"))
+                .down(include_str!("../../migrations/11/down.sql")),
+            M::up(include_str!("../../migrations/12/up.sql"))
+                .down(include_str!("../../migrations/12/down.sql")),
+            M::up(include_str!("../../migrations/13/up.sql"))
+                .down(include_str!("../../migrations/13/down.sql")),
+            M::up(include_str!("../../migrations/14/up.sql"))
+                .down(include_str!("../../migrations/14/down.sql")),
+            M::up(include_str!("../../migrations/15/up.sql"))
+                .down(include_str!("../../migrations/15/down.sql")),
         ]);
         let mut conn = pool.get().unwrap();
         info!("Running migrations");
@@ -68,26 +100,64 @@ pub fn run(config: Config, config_path: PathBuf) {
         None => "http://localhost".to_string(),
     };
 
-    let sessions = if config.sessions_dir.exists() && config.sessions_dir.is_dir() {
-        ::sessions::SessionSet::load(config.sessions_dir).expect("failed to load sessions")
-    } else {
-        ::sessions::SessionSet::new(config.sessions_dir).expect("failed to create sessions")
+    // Admins can override the configured signup setting at runtime
+    let signup = match db::read::site_setting(&pool, "signup").unwrap() {
+        Some(s) => s == "true",
+        None => config.signup.unwrap_or(false),
     };
 
     let ctx = Context {
         db_pool: pool,
         mount: mount,
-        logins: Arc::new(Mutex::new(sessions)),
+        pending_logins: Mutex::new(HashMap::new()),
+        pending_totp: Mutex::new(HashMap::new()),
         name: config.name.unwrap_or_else(|| String::from("Valentine")),
         url: url,
         ssh: config.ssh,
-        signup: config.signup.unwrap_or(false),
+        signup: AtomicBool::new(signup),
+        signup_domains: config.signup_domains.unwrap_or_default(),
+        proxy_auth: config.proxy_auth,
+        oidc: config.oidc,
+        oidc_logins: Mutex::new(HashMap::new()),
+        oidc_links: Mutex::new(HashMap::new()),
+        highlighter: highlight::Highlighter::new(),
+        commit_counts: git::commit_counter(),
+        disk_usage: Mutex::new(None),
+        ldap: config.ldap,
         repo_dir: config.repo_dir,
         ssh_dir: ssh_dir,
         bin_path: env::current_exe().unwrap(),
         config_path: config_path,
     };
 
+    // Install the managed git hooks, they need updating whenever the binary moves
+    for (owner, repo) in db::read::all_repos(&ctx.db_pool).unwrap() {
+        if let Err(e) = git::install_hooks(&ctx, &owner, &repo) {
+            warn!("unable to install hooks for {}/{}: {}", owner, repo, e);
+        }
+    }
+
+    // Old auth events are only kept for a while
+    let pool = ctx.db_pool.clone();
+    thread::spawn(move || loop {
+        if let Err(e) = throttle::prune_auth_events(&pool) {
+            warn!("unable to prune auth events: {}", e);
+        }
+        thread::sleep(Duration::from_secs(24 * 60 * 60));
+    });
+
+    // Disable users that were removed from the LDAP directory
+    if let Some(ldap) = ctx.ldap.clone() {
+        let pool = ctx.db_pool.clone();
+        let interval = Duration::from_secs(ldap.sync_minutes.unwrap_or(60) * 60);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = ldap::sync(&pool, &ldap) {
+                warn!("unable to sync users with the directory: {}", e);
+            }
+        });
+    }
+
     let mut router = Router::mount(ctx.mount.clone());
     router.set_not_found_handler(Arc::new(not_found));
     router.set_internal_error_handler(Arc::new(internal_error));
@@ -95,6 +165,7 @@ pub fn run(config: Config, config_path: PathBuf) {
     router!{
         router,
         get "/" => home,
+        get "/highlight.css" => highlight_css,
         get "/explore" => explore,
         get "/{user}" => user,
         get "/{user}/{repo}" => repo::view,
@@ -104,25 +175,84 @@ pub fn run(config: Config, config_path: PathBuf) {
         get "/{user}/{repo}/refs/{id}" => repo::commit,
         get "/{user}/{repo}/refs/{id}/{*filepath}" => repo::src,
         get "/{user}/{repo}/refs/{id}/raw/{*filepath}" => repo::raw,
+        get "/{user}/{repo}/blame/{id}/{*filepath}" => repo::blame,
+        get "/{user}/{repo}/history/{id}/{*filepath}" => repo::history,
+        get "/{user}/{repo}/compare" => repo::compare_form,
+        get "/{user}/{repo}/compare/{*range}" => repo::compare,
 
         // Git pull
         // TODO: use regex to assert that `repo` ends with .git
         get "/{user}/{repo}/info/refs" => git_routes::pull_handshake,
         post "/{user}/{repo}/git-upload-pack" => git_routes::pull,
 
+        // Git push
+        post "/{user}/{repo}/git-receive-pack" => git_routes::push,
+
+        // Admin
+        get "/admin" => admin::panel,
+        post "/admin/signup" => admin::toggle_signup,
+        post r"/admin/users/{id:\d+}/disable" => admin::toggle_user,
+        post r"/admin/users/{id:\d+}/ldap" => admin::link_ldap,
+        post r"/admin/users/{id:\d+}/delete" => admin::delete_user,
+        post r"/admin/repos/{id:\d+}/delete" => admin::delete_repo,
+        post r"/admin/repos/{id:\d+}/transfer" => admin::transfer_repo,
+        post r"/admin/invites/{id:\d+}/delete" => admin::delete_invite,
+        post r"/admin/lockouts/{id:\d+}/clear" => admin::clear_lockout,
+
         // User
+        get "/login/oidc" => user::login_oidc,
+        get "/login/oidc/callback" => user::login_oidc_callback,
+        get "/login/oidc/link" => user::login_oidc_link,
+        post "/login/oidc/link" => user::login_oidc_link_post,
+        get "/login/2fa" => user::login_2fa,
+        post "/login/2fa" => user::login_2fa_post,
         get "/signup" => user::signup,
         post "/signup" => user::signup_post,
         get "/login" => user::login,
         post "/login" => user::login_post,
         get "/logout" => user::logout,
+        post "/logout" => user::logout_post,
         get "/settings" => user::settings,
+        get "/settings/sessions" => user::sessions,
+        post "/settings/sessions/revoke" => user::revoke_session,
+        post "/settings/sessions/revoke-all" => user::revoke_all_sessions,
+        post "/settings/password" => user::change_password,
+        post "/settings/email" => user::change_email,
+        post "/settings/delete" => user::delete_account,
         post "/settings/add-ssh-key" => user::add_ssh_key,
-        get r"/settings/delete-ssh-key/{id:\d+}" => user::delete_ssh_key,
+        post r"/settings/delete-ssh-key/{id:\d+}" => user::delete_ssh_key,
+        post "/settings/add-signing-key" => user::add_signing_key,
+        get "/settings/2fa" => user::totp_setup,
+        post "/settings/2fa/enable" => user::totp_enable,
+        post "/settings/2fa/disable" => user::totp_disable,
+        post "/settings/2fa/recovery-codes" => user::recovery_codes,
+        post "/settings/invites/new" => user::new_invite,
+        post r"/settings/invites/{id:\d+}/delete" => user::delete_invite,
+        post r"/settings/transfers/{id:\d+}/accept" => user::repo::accept_transfer,
+        post r"/settings/transfers/{id:\d+}/decline" => user::repo::decline_transfer,
+        post r"/settings/delete-signing-key/{id:\d+}" => user::delete_signing_key,
         get "/repo/new" => user::repo::new,
         post "/repo/new" => user::repo::new_post,
+        get "/org/new" => user::org::new,
+        post "/org/new" => user::org::new_post,
+        get "/org/{org}/settings" => user::org::settings,
+        post "/org/{org}/members/add" => user::org::add_member,
+        post "/org/{org}/members/remove" => user::org::remove_member,
+        post "/org/{org}/teams/new" => user::org::new_team,
+        get "/org/{org}/teams/{team}" => user::org::team,
+        post "/org/{org}/teams/{team}/delete" => user::org::delete_team,
+        post "/org/{org}/teams/{team}/members/add" => user::org::add_team_member,
+        post "/org/{org}/teams/{team}/members/remove" => user::org::remove_team_member,
+        post "/org/{org}/teams/{team}/repos/add" => user::org::add_team_repo,
+        post "/org/{org}/teams/{team}/repos/remove" => user::org::remove_team_repo,
         get "/{user}/{repo}/settings" => user::repo::settings,
         post "/{user}/{repo}/settings/name" => user::repo::settings_name,
+        post "/{user}/{repo}/settings/collaborators/add" => user::repo::add_collaborator,
+        post "/{user}/{repo}/settings/collaborators/remove" => user::repo::remove_collaborator,
+        post "/{user}/{repo}/settings/branches/add" => user::repo::add_branch_rule,
+        post "/{user}/{repo}/settings/branches/delete" => user::repo::delete_branch_rule,
+        post "/{user}/{repo}/settings/transfer" => user::repo::transfer,
+        post "/{user}/{repo}/settings/transfer/cancel" => user::repo::cancel_transfer,
         post "/{user}/{repo}/settings/delete" => user::repo::delete,
     }
 
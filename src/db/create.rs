@@ -9,6 +9,115 @@ pub fn user(pool: &Pool, user: &NewUser) -> Result<()> {
     Ok(())
 }
 
+/// Creates a user from an invitation, claiming the invitation in the same
+/// transaction so that it can only be used once. Returns false if the
+/// invitation is invalid, used or expired.
+pub fn invited_user(pool: &Pool, user: &NewUser, code: &str) -> Result<bool> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    tx.execute(query!("INSERT INTO users (username, password, email, num_repos) VALUES (?1, ?2, ?3, ?4)"),
+               params![user.username, user.password, user.email, user.num_repos])?;
+    let id = tx.last_insert_rowid();
+    let now = ::chrono::Utc::now().naive_utc();
+    let claimed = tx.execute(query!("UPDATE invites SET used_by = ?1 WHERE code = ?2 AND used_by IS NULL AND expires > ?3"),
+                             params![id, code, now])?;
+    if claimed == 0 {
+        return Ok(false);
+    }
+    tx.commit()?;
+    Ok(true)
+}
+
+pub fn invite(pool: &Pool, invite: &NewInvite) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("INSERT INTO invites (code, created_by, expires) VALUES (?1, ?2, ?3)"),
+                 params![invite.code, invite.created_by, invite.expires])?;
+    Ok(())
+}
+
+/// Creates an organization owned by `creator`. This is done in a transaction
+/// so an organization never exists without an owner.
+pub fn org(pool: &Pool, org: &NewOrg, creator: i32) -> Result<()> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    tx.execute(query!("INSERT INTO users (username, password, email, num_repos, is_org) VALUES (?1, '', ?2, 0, true)"),
+               params![org.name, org.email])?;
+    let id = tx.last_insert_rowid();
+    tx.execute(query!("INSERT INTO org_members (org, member, is_owner) VALUES (?1, ?2, true)"),
+               params![id, creator])?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn org_member(pool: &Pool, org: i32, member: i32, is_owner: bool) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("INSERT INTO org_members (org, member, is_owner) VALUES (?1, ?2, ?3)
+                         ON CONFLICT (org, member) DO UPDATE SET is_owner = ?3"),
+                 params![org, member, is_owner])?;
+    Ok(())
+}
+
+pub fn team(pool: &Pool, team: &NewTeam) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("INSERT INTO teams (org, name, access) VALUES (?1, ?2, ?3)"),
+                 params![team.org, team.name, team.access])?;
+    Ok(())
+}
+
+pub fn team_member(pool: &Pool, team: i32, member: i32) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("INSERT OR IGNORE INTO team_members (team, member) VALUES (?1, ?2)"),
+                 params![team, member])?;
+    Ok(())
+}
+
+pub fn team_repo(pool: &Pool, team: i32, repo: i64) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("INSERT OR IGNORE INTO team_repos (team, repo) VALUES (?1, ?2)"),
+                 params![team, repo])?;
+    Ok(())
+}
+
+/// Requests a transfer of `repo` to `new_owner`, replacing any earlier
+/// request for the same repo.
+pub fn repo_transfer(pool: &Pool, repo: i64, new_owner: i32) -> Result<()> {
+    let conn = pool.get()?;
+    let now = ::chrono::Utc::now().naive_utc();
+    conn.execute(query!("INSERT INTO repo_transfers (repo, new_owner, created) VALUES (?1, ?2, ?3)
+                         ON CONFLICT (repo) DO UPDATE SET new_owner = ?2, created = ?3"),
+                 params![repo, new_owner, now])?;
+    Ok(())
+}
+
+pub fn collaborator(pool: &Pool, repo: i64, member: i32, access: Access) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("INSERT INTO collaborators (repo, member, access) VALUES (?1, ?2, ?3)
+                         ON CONFLICT (repo, member) DO UPDATE SET access = ?3"),
+                 params![repo, member, access])?;
+    Ok(())
+}
+
+pub fn branch_rule(pool: &Pool, repo: i64, rule: &NewBranchRule, users: &[i32], teams: &[i32])
+    -> Result<()>
+{
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    tx.execute(query!("INSERT INTO protected_branches (repo, pattern, no_force_push, no_deletion, require_signed)
+                       VALUES (?1, ?2, ?3, ?4, ?5)"),
+               params![repo, rule.pattern, rule.no_force_push, rule.no_deletion, rule.require_signed])?;
+    let id = tx.last_insert_rowid();
+    for user in users {
+        tx.execute(query!("INSERT INTO protected_branch_pushers (rule, member) VALUES (?1, ?2)"),
+                   params![id, user])?;
+    }
+    for team in teams {
+        tx.execute(query!("INSERT INTO protected_branch_pushers (rule, team) VALUES (?1, ?2)"),
+                   params![id, team])?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
 pub fn public_key(pool: &Pool, key: &NewSshKey) -> Result<SshKey> {
     let conn = pool.get()?;
     let mut stmt = conn.prepare(query!("INSERT INTO public_keys (owner, name, fingerprint, content) VALUES (?1, ?2, ?3, ?4) RETURNING id"))?;
@@ -22,10 +131,54 @@ pub fn public_key(pool: &Pool, key: &NewSshKey) -> Result<SshKey> {
     })
 }
 
+pub fn signing_key(pool: &Pool, key: &NewSigningKey) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("INSERT INTO signing_keys (owner, name, kind, fingerprint, content) VALUES (?1, ?2, ?3, ?4, ?5)"),
+                 params![key.owner, key.name, key.kind, key.fingerprint, key.content])?;
+    Ok(())
+}
+
 pub fn repo(pool: &Pool, repo: &Repo) -> Result<()> {
     let conn = pool.get()?;
-    conn.execute(query!("INSERT INTO repos (name, description, owner, private) VALUES (?1, ?2, ?3, ?4)"),
-                 params![repo.name, repo.description, repo.owner, repo.private])?;
+    conn.execute(query!("INSERT INTO repos (name, description, owner, visibility) VALUES (?1, ?2, ?3, ?4)"),
+                 params![repo.name, repo.description, repo.owner, repo.visibility])?;
     conn.execute(query!("UPDATE users SET num_repos = num_repos + 1 WHERE id = ?1"), params![repo.owner])?;
     Ok(())
 }
+
+pub fn auth_event(pool: &Pool, event: &str, username: &str, ip: &str, source: &str) -> Result<()> {
+    let conn = pool.get()?;
+    let now = ::chrono::Utc::now().naive_utc();
+    conn.execute(query!("INSERT INTO auth_events (created, event, username, ip, source) VALUES (?1, ?2, ?3, ?4, ?5)"),
+                 params![now, event, username, ip, source])?;
+    Ok(())
+}
+
+/// Replaces the recovery codes of `user` with the given hashes.
+pub fn recovery_codes(pool: &Pool, user: i32, hashes: &[String]) -> Result<()> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    tx.execute(query!("DELETE FROM recovery_codes WHERE owner = ?1"), params![user])?;
+    for hash in hashes {
+        tx.execute(query!("INSERT INTO recovery_codes (owner, hash) VALUES (?1, ?2)"), params![user, hash])?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn session(pool: &Pool, id: &str, user: i32, user_agent: &str, ip: &str,
+               lifetime: ::chrono::Duration) -> Result<()> {
+    let conn = pool.get()?;
+    let now = ::chrono::Utc::now().naive_utc();
+    conn.execute(query!("INSERT INTO user_sessions (id, user, created, last_seen, expires, user_agent, ip)
+                         VALUES (?1, ?2, ?3, ?3, ?4, ?5, ?6)"),
+                 params![id, user, now, now + lifetime, user_agent, ip])?;
+    Ok(())
+}
+
+pub fn oidc_identity(pool: &Pool, user: i32, issuer: &str, subject: &str) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("INSERT INTO oidc_identities (user, issuer, subject) VALUES (?1, ?2, ?3)"),
+                 params![user, issuer, subject])?;
+    Ok(())
+}
@@ -5,22 +5,173 @@ pub fn user(pool: &Pool, username: &str) -> Result<()> {
     let owner = read::user_id(pool, username)?;
 
     let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM collaborators WHERE repo IN (SELECT id FROM repos WHERE owner = ?1)"),
+                 params![owner])?;
+    conn.execute(query!("DELETE FROM team_repos WHERE repo IN (SELECT id FROM repos WHERE owner = ?1)"),
+                 params![owner])?;
+    conn.execute(query!("DELETE FROM protected_branch_pushers WHERE rule IN
+                         (SELECT protected_branches.id FROM protected_branches
+                          JOIN repos ON repos.id = protected_branches.repo WHERE repos.owner = ?1)"),
+                 params![owner])?;
+    conn.execute(query!("DELETE FROM protected_branches WHERE repo IN (SELECT id FROM repos WHERE owner = ?1)"),
+                 params![owner])?;
+    conn.execute(query!("DELETE FROM repo_transfers WHERE new_owner = ?1 OR repo IN (SELECT id FROM repos WHERE owner = ?1)"),
+                 params![owner])?;
+    conn.execute(query!("DELETE FROM repo_redirects WHERE owner = ?1 OR repo IN (SELECT id FROM repos WHERE owner = ?1)"),
+                 params![owner])?;
     conn.execute(query!("DELETE FROM repos WHERE owner = ?1"), params![owner])?;
+    // Teams of an organization
+    conn.execute(query!("DELETE FROM team_members WHERE team IN (SELECT id FROM teams WHERE org = ?1)"),
+                 params![owner])?;
+    conn.execute(query!("DELETE FROM teams WHERE org = ?1"), params![owner])?;
+    conn.execute(query!("DELETE FROM public_keys WHERE owner = ?1"), params![owner])?;
+    conn.execute(query!("DELETE FROM org_members WHERE org = ?1 OR member = ?1"), params![owner])?;
+    conn.execute(query!("DELETE FROM team_members WHERE member = ?1"), params![owner])?;
+    conn.execute(query!("DELETE FROM collaborators WHERE member = ?1"), params![owner])?;
+    conn.execute(query!("DELETE FROM signing_keys WHERE owner = ?1"), params![owner])?;
+    conn.execute(query!("DELETE FROM recovery_codes WHERE owner = ?1"), params![owner])?;
+    conn.execute(query!("DELETE FROM user_sessions WHERE user = ?1"), params![owner])?;
+    conn.execute(query!("DELETE FROM oidc_identities WHERE user = ?1"), params![owner])?;
+    conn.execute(query!("DELETE FROM invites WHERE created_by = ?1 OR used_by = ?1"), params![owner])?;
     conn.execute(query!("DELETE FROM users WHERE id = ?1"), params![owner])?;
     Ok(())
 }
 
 pub fn repo(pool: &Pool, username: &str, repo_name: &str) -> Result<()> {
     let owner = read::user_id(pool, username)?;
+    let repo = read::repo_id(pool, username, repo_name)?;
 
     let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM team_repos WHERE repo = ?1"), params![repo])?;
+    conn.execute(query!("DELETE FROM collaborators WHERE repo = ?1"), params![repo])?;
+    conn.execute(query!("DELETE FROM protected_branch_pushers WHERE rule IN (SELECT id FROM protected_branches WHERE repo = ?1)"),
+                 params![repo])?;
+    conn.execute(query!("DELETE FROM protected_branches WHERE repo = ?1"), params![repo])?;
+    conn.execute(query!("DELETE FROM repo_transfers WHERE repo = ?1"), params![repo])?;
+    conn.execute(query!("DELETE FROM repo_redirects WHERE repo = ?1"), params![repo])?;
     conn.execute(query!("DELETE FROM repos WHERE owner = ?1 AND name = ?2"), params![owner, repo_name])?;
     conn.execute(query!("UPDATE users SET num_repos = num_repos - 1 WHERE id = ?1"), params![owner])?;
     Ok(())
 }
 
+pub fn repo_transfer(pool: &Pool, id: i32) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM repo_transfers WHERE id = ?1"), params![id])?;
+    Ok(())
+}
+
+pub fn invite(pool: &Pool, id: i32) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM invites WHERE id = ?1"), params![id])?;
+    Ok(())
+}
+
 pub fn public_key(pool: &Pool, id: i32) -> Result<()> {
     let conn = pool.get()?;
     conn.execute(query!("DELETE FROM public_keys WHERE id = ?1"), params![id])?;
     Ok(())
 }
+
+pub fn signing_key(pool: &Pool, id: i32) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM signing_keys WHERE id = ?1"), params![id])?;
+    Ok(())
+}
+
+pub fn org_member(pool: &Pool, org: i32, member: i32) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM team_members WHERE member = ?2 AND team IN (SELECT id FROM teams WHERE org = ?1)"),
+                 params![org, member])?;
+    conn.execute(query!("DELETE FROM org_members WHERE org = ?1 AND member = ?2"), params![org, member])?;
+    Ok(())
+}
+
+pub fn team(pool: &Pool, team: i32) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM team_members WHERE team = ?1"), params![team])?;
+    conn.execute(query!("DELETE FROM protected_branch_pushers WHERE team = ?1"), params![team])?;
+    conn.execute(query!("DELETE FROM team_repos WHERE team = ?1"), params![team])?;
+    conn.execute(query!("DELETE FROM teams WHERE id = ?1"), params![team])?;
+    Ok(())
+}
+
+pub fn team_member(pool: &Pool, team: i32, member: i32) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM team_members WHERE team = ?1 AND member = ?2"), params![team, member])?;
+    Ok(())
+}
+
+pub fn team_repo(pool: &Pool, team: i32, repo: i64) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM team_repos WHERE team = ?1 AND repo = ?2"), params![team, repo])?;
+    Ok(())
+}
+
+pub fn collaborator(pool: &Pool, repo: i64, member: i32) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM collaborators WHERE repo = ?1 AND member = ?2"), params![repo, member])?;
+    Ok(())
+}
+
+pub fn branch_rule(pool: &Pool, repo: i64, id: i32) -> Result<()> {
+    let conn = pool.get()?;
+    let deleted = conn.execute(query!("DELETE FROM protected_branches WHERE repo = ?1 AND id = ?2"), params![repo, id])?;
+    if deleted > 0 {
+        conn.execute(query!("DELETE FROM protected_branch_pushers WHERE rule = ?1"), params![id])?;
+    }
+    Ok(())
+}
+
+pub fn throttle(pool: &Pool, kind: &str, key: &str) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM login_throttles WHERE kind = ?1 AND key = ?2"), params![kind, key])?;
+    Ok(())
+}
+
+/// Deletes the auth events from before `before`.
+pub fn auth_events(pool: &Pool, before: ::chrono::NaiveDateTime) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM auth_events WHERE created < ?1"), params![before])?;
+    Ok(())
+}
+
+pub fn throttle_by_id(pool: &Pool, id: i32) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM login_throttles WHERE id = ?1"), params![id])?;
+    Ok(())
+}
+
+/// Uses up a recovery code, returns false if `user` has no such code.
+pub fn recovery_code(pool: &Pool, user: i32, hash: &str) -> Result<bool> {
+    let conn = pool.get()?;
+    let deleted = conn.execute(query!("DELETE FROM recovery_codes WHERE owner = ?1 AND hash = ?2"),
+                               params![user, hash])?;
+    Ok(deleted > 0)
+}
+
+pub fn recovery_codes(pool: &Pool, user: i32) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM recovery_codes WHERE owner = ?1"), params![user])?;
+    Ok(())
+}
+
+pub fn session(pool: &Pool, id: &str) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM user_sessions WHERE id = ?1"), params![id])?;
+    Ok(())
+}
+
+/// Revokes every session of `user`, except for `keep` if given.
+pub fn sessions(pool: &Pool, user: i32, keep: Option<&str>) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("DELETE FROM user_sessions WHERE user = ?1 AND (?2 IS NULL OR id != ?2)"),
+                 params![user, keep])?;
+    Ok(())
+}
+
+pub fn expired_sessions(pool: &Pool) -> Result<()> {
+    let conn = pool.get()?;
+    let now = ::chrono::Utc::now().naive_utc();
+    conn.execute(query!("DELETE FROM user_sessions WHERE expires <= ?1"), params![now])?;
+    Ok(())
+}
@@ -5,7 +5,7 @@ use super::Pool;
 
 pub fn check_login(pool: &Pool, login: &Login) -> Result<bool> {
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT password FROM users WHERE username = ?1"))?;
+    let mut stmt = conn.prepare(query!("SELECT password FROM users WHERE username = ?1 AND is_org = false AND disabled = false"))?;
     let password: String = match stmt.query_row(params![login.username], |row| row.get(0)) {
         Ok(v) => v,
         Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(false),
@@ -20,16 +20,73 @@ pub fn user_id(pool: &Pool, username: &str) -> Result<i32> {
     Ok(stmt.query_row(params![username], |row| row.get(0))?)
 }
 
-pub fn user_name(pool: &Pool, id: i32) -> Result<String> {
+pub fn user_name(pool: &Pool, id: i32) -> Result<Option<String>> {
     let conn = pool.get()?;
     let mut stmt = conn.prepare(query!("SELECT username FROM users WHERE id = ?1"))?;
-    Ok(stmt.query_row(params![id], |row| row.get(0))?)
+    match stmt.query_row(params![id], |row| row.get(0)) {
+        Ok(name) => Ok(Some(name)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
 }
 
 pub fn user_exists(pool: &Pool, username: &str) -> Result<bool> {
     Ok(user_id(pool, username).is_ok())
 }
 
+pub fn is_org(pool: &Pool, name: &str) -> Result<bool> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT is_org FROM users WHERE username = ?1"))?;
+    match stmt.query_row(params![name], |row| row.get(0)) {
+        Ok(is_org) => Ok(is_org),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Returns the names of the organizations that `user` is an owner of.
+pub fn owned_orgs(pool: &Pool, user: i32) -> Result<Vec<String>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT users.username FROM org_members JOIN users ON users.id = org_members.org
+                                        WHERE org_members.member = ?1 AND org_members.is_owner = true
+                                        ORDER BY users.username"))?;
+    let rows = stmt.query_map(params![user], |row| row.get(0))?;
+    let mut orgs = Vec::new();
+    for r in rows {
+        orgs.push(r?);
+    }
+    Ok(orgs)
+}
+
+pub fn org_members(pool: &Pool, org: i32) -> Result<Vec<OrgMember>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT users.username, org_members.is_owner FROM org_members
+                                        JOIN users ON users.id = org_members.member
+                                        WHERE org_members.org = ?1 ORDER BY users.username"))?;
+    let rows = stmt.query_map(params![org], |row| {
+        Ok(OrgMember {
+            username: row.get(0)?,
+            is_owner: row.get(1)?,
+        })
+    })?;
+    let mut members = Vec::new();
+    for r in rows {
+        members.push(r?);
+    }
+    Ok(members)
+}
+
+/// Returns `Some(is_owner)` if `user` is a member of `org`.
+pub fn org_role(pool: &Pool, org: i32, user: i32) -> Result<Option<bool>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT is_owner FROM org_members WHERE org = ?1 AND member = ?2"))?;
+    match stmt.query_row(params![org, user], |row| row.get(0)) {
+        Ok(is_owner) => Ok(Some(is_owner)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
 pub fn repo_id(pool: &Pool, username: &str, reponame: &str) -> Result<Option<i64>> {
     let owner = user_id(pool, username)?;
 
@@ -46,35 +103,43 @@ pub fn repo_exists(pool: &Pool, username: &str, reponame: &str) -> Result<bool>
     Ok(repo_id(pool, username, reponame)?.is_some())
 }
 
-pub fn repo_is_private(pool: &Pool, username: &str, reponame: &str) -> Result<bool> {
+/// Returns the visibility of a repo, repos that don't exist are treated as
+/// private.
+pub fn repo_visibility(pool: &Pool, username: &str, reponame: &str) -> Result<Visibility> {
     let owner = user_id(pool, username)?;
 
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT private FROM repos WHERE owner = ?1 AND name = ?2"))?;
+    let mut stmt = conn.prepare(query!("SELECT visibility FROM repos WHERE owner = ?1 AND name = ?2"))?;
     match stmt.query_row(params![owner, reponame], |row| row.get(0)) {
-        Ok(private) => Ok(private),
-        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(true),
+        Ok(visibility) => Ok(visibility),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(Visibility::Private),
         Err(e) => Err(Error::from(e)),
     }
 }
 
-pub fn user<'a, 'b>(pool: &Pool, username: &'b str, ctx: &'a Context, auth: bool)
+/// Lists the repos of `username`. Internal repos are included if the viewer
+/// is `logged_in`, private repos if the viewer is `auth`orized.
+pub fn user<'a, 'b>(pool: &Pool, username: &'b str, ctx: &'a Context, logged_in: bool, auth: bool)
     -> Result<Option<UserTmpl<'a, 'b>>>
 {
     let owner = user_id(pool, username)?;
-
-    let conn = pool.get()?;
-    let mut stmt = if !auth {
-        conn.prepare(query!("SELECT name, description, owner, private FROM repos WHERE owner = ?1 AND private = false ORDER BY last_updated DESC"))?
+    let is_org = is_org(pool, username)?;
+    let members = if is_org {
+        org_members(pool, owner)?
     } else {
-        conn.prepare(query!("SELECT name, description, owner, private FROM repos WHERE owner = ?1 ORDER BY last_updated DESC"))?
+        Vec::new()
     };
-    let rows = stmt.query_map(params![owner], |row| {
+
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT name, description, owner, visibility FROM repos
+                                        WHERE owner = ?1 AND (visibility = 'public' OR (?2 AND visibility = 'internal') OR ?3)
+                                        ORDER BY last_updated DESC"))?;
+    let rows = stmt.query_map(params![owner, logged_in, auth], |row| {
         Ok(Repo {
             name: row.get(0)?,
             description: row.get(1)?,
             owner: row.get(2)?,
-            private: row.get(3)?,
+            visibility: row.get(3)?,
         })
     })?;
     let mut repos = Vec::new();
@@ -86,21 +151,25 @@ pub fn user<'a, 'b>(pool: &Pool, username: &'b str, ctx: &'a Context, auth: bool
         mount: &ctx.mount,
         username: username,
         repos: repos,
+        is_org: is_org,
+        members: members,
     }))
 
 }
 
-pub fn users<'a>(pool: &Pool, ctx: &'a Context) -> Result<ExploreTmpl<'a>> {
+pub fn users<'a>(pool: &Pool, ctx: &'a Context, logged_in: bool) -> Result<ExploreTmpl<'a>> {
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT name, owner FROM repos WHERE private = false ORDER BY last_updated DESC"))?;
-    let rows = stmt.query_map(params![], |row| {
+    let mut stmt = conn.prepare(query!("SELECT name, owner FROM repos WHERE visibility = 'public' OR (?1 AND visibility = 'internal')
+                                        ORDER BY last_updated DESC"))?;
+    let rows = stmt.query_map(params![logged_in], |row| {
         Ok((row.get(0)?, row.get(1)?))
     })?;
     let mut repos = Vec::new();
     for r in rows {
         let (name, owner) = r?;
-        let owner = user_name(pool, owner)?;
-        repos.push((name, owner));
+        if let Some(owner) = user_name(pool, owner)? {
+            repos.push((name, owner));
+        }
     }
 
     Ok(ExploreTmpl {
@@ -113,13 +182,13 @@ pub fn repo(pool: &Pool, username: &str, reponame: &str) -> Result<Option<Repo>>
     let owner = user_id(pool, username)?;
 
     let conn = pool.get()?;
-    let mut stmt = conn.prepare(query!("SELECT name, description, owner, private FROM repos WHERE owner = ?1 AND name = ?2"))?;
+    let mut stmt = conn.prepare(query!("SELECT name, description, owner, visibility FROM repos WHERE owner = ?1 AND name = ?2"))?;
     match stmt.query_row(params![owner, reponame], |row|
                          Ok(Repo {
                              name: row.get(0)?,
                              description: row.get(1)?,
                              owner: row.get(2)?,
-                             private: row.get(3)?,
+                             visibility: row.get(3)?,
                          })) {
         Ok(repo) => Ok(Some(repo)),
         Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
@@ -127,14 +196,12 @@ pub fn repo(pool: &Pool, username: &str, reponame: &str) -> Result<Option<Repo>>
     }
 }
 
-pub fn settings<'a, 'b>(pool: &Pool, username: &'b str, ctx: &'a Context)
+pub fn settings<'a, 'b>(pool: &Pool, owner: i32, username: &'b str, ctx: &'a Context)
     -> Result<UserSettings<'a, 'b>>
 {
-    let owner = user_id(pool, username)?;
-
     let conn = pool.get()?;
     let mut stmt = conn.prepare(query!("SELECT email FROM users WHERE id = ?1"))?;
-    let email = stmt.query_row(params![username], |row| row.get(0))?;
+    let email = stmt.query_row(params![owner], |row| row.get(0))?;
 
     let mut stmt = conn.prepare(query!("SELECT id, owner, name, fingerprint, content FROM public_keys WHERE owner = ?1"))?;
     let rows = stmt.query_map(params![owner], |row| {
@@ -151,15 +218,60 @@ pub fn settings<'a, 'b>(pool: &Pool, username: &'b str, ctx: &'a Context)
         keys.push(r?);
     }
 
+    let signing_keys = signing_keys(pool, Some(owner))?;
+    let transfers = incoming_transfers(pool, owner)?;
+    let invites = invites(pool, Some(owner))?;
+    let totp_enabled = totp(pool, owner)?.is_some();
+    let recovery_codes_left = recovery_codes_left(pool, owner)?;
+
     Ok(UserSettings {
+        url: &ctx.url,
         mount: &ctx.mount,
         username: username,
         email: email,
         keys: keys,
+        signing_keys: signing_keys,
+        transfers: transfers,
+        invites: invites,
+        totp_enabled: totp_enabled,
+        recovery_codes_left: recovery_codes_left,
+        csrf: String::new(),
         //auth: true,
     })
 }
 
+/// Returns the signing keys of `owner`, or of every user if `owner` is `None`.
+pub fn signing_keys(pool: &Pool, owner: Option<i32>) -> Result<Vec<SigningKey>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id, owner, name, kind, fingerprint, content FROM signing_keys
+                                        WHERE ?1 IS NULL OR owner = ?1"))?;
+    let rows = stmt.query_map(params![owner], |row| {
+        Ok(SigningKey {
+            id: row.get(0)?,
+            owner: row.get(1)?,
+            name: row.get(2)?,
+            kind: row.get(3)?,
+            fingerprint: row.get(4)?,
+            content: row.get(5)?,
+        })
+    })?;
+    let mut keys = Vec::new();
+    for r in rows {
+        keys.push(r?);
+    }
+    Ok(keys)
+}
+
+pub fn user_owns_signing_key(pool: &Pool, user: i32, id: i32) -> Result<bool> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT owner FROM signing_keys WHERE id = ?1"))?;
+    match stmt.query_row(params![id], |row| row.get::<usize, i32>(0)) {
+        Ok(owner) => Ok(owner == user),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
 pub fn user_by_key_id(pool: &Pool, id: i32) -> Result<Option<i32>> {
     let conn = pool.get()?;
 
@@ -171,8 +283,7 @@ pub fn user_by_key_id(pool: &Pool, id: i32) -> Result<Option<i32>> {
     }
 }
 
-pub fn user_owns_key(pool: &Pool, username: &str, id: i32) -> Result<bool> {
-    let user = user_id(pool, username)?;
+pub fn user_owns_key(pool: &Pool, user: i32, id: i32) -> Result<bool> {
     let owner = match user_by_key_id(pool, id)? {
         Some(o) => o,
         _ => return Ok(false),
@@ -181,13 +292,600 @@ pub fn user_owns_key(pool: &Pool, username: &str, id: i32) -> Result<bool> {
     Ok(owner == user)
 }
 
-pub fn user_owns_repo(pool: &Pool, owner: i32, reponame: &str) -> Result<bool> {
+pub fn teams(pool: &Pool, org: i32) -> Result<Vec<Team>> {
     let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id, name, access FROM teams WHERE org = ?1 ORDER BY name"))?;
+    let rows = stmt.query_map(params![org], |row| {
+        Ok(Team {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            access: row.get(2)?,
+        })
+    })?;
+    let mut teams = Vec::new();
+    for r in rows {
+        teams.push(r?);
+    }
+    Ok(teams)
+}
 
-    let mut stmt = conn.prepare(query!("SELECT id FROM repos WHERE owner = ?1 AND name = ?2"))?;
-    match stmt.query_row(params![owner, reponame], |row| row.get::<usize, i32>(0)) {
-        Ok(_) => Ok(true),
-        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+pub fn team(pool: &Pool, org: i32, name: &str) -> Result<Option<Team>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id, name, access FROM teams WHERE org = ?1 AND name = ?2"))?;
+    match stmt.query_row(params![org, name], |row|
+                         Ok(Team {
+                             id: row.get(0)?,
+                             name: row.get(1)?,
+                             access: row.get(2)?,
+                         })) {
+        Ok(team) => Ok(Some(team)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+pub fn team_members(pool: &Pool, team: i32) -> Result<Vec<String>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT users.username FROM team_members JOIN users ON users.id = team_members.member
+                                        WHERE team_members.team = ?1 ORDER BY users.username"))?;
+    let rows = stmt.query_map(params![team], |row| row.get(0))?;
+    let mut members = Vec::new();
+    for r in rows {
+        members.push(r?);
+    }
+    Ok(members)
+}
+
+pub fn team_repos(pool: &Pool, team: i32) -> Result<Vec<String>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT repos.name FROM team_repos JOIN repos ON repos.id = team_repos.repo
+                                        WHERE team_repos.team = ?1 ORDER BY repos.name"))?;
+    let rows = stmt.query_map(params![team], |row| row.get(0))?;
+    let mut repos = Vec::new();
+    for r in rows {
+        repos.push(r?);
+    }
+    Ok(repos)
+}
+
+fn repo_transfer_row(row: &rusqlite::Row) -> rusqlite::Result<RepoTransfer> {
+    Ok(RepoTransfer {
+        id: row.get(0)?,
+        repo: row.get(1)?,
+        owner: row.get(2)?,
+        name: row.get(3)?,
+        new_owner_id: row.get(4)?,
+        new_owner: row.get(5)?,
+    })
+}
+
+/// Returns the pending transfer with the given id.
+pub fn repo_transfer(pool: &Pool, id: i32) -> Result<Option<RepoTransfer>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT repo_transfers.id, repo_transfers.repo, owners.username, repos.name,
+                                               repo_transfers.new_owner, new_owners.username
+                                        FROM repo_transfers
+                                        JOIN repos ON repos.id = repo_transfers.repo
+                                        JOIN users AS owners ON owners.id = repos.owner
+                                        JOIN users AS new_owners ON new_owners.id = repo_transfers.new_owner
+                                        WHERE repo_transfers.id = ?1"))?;
+    match stmt.query_row(params![id], repo_transfer_row) {
+        Ok(transfer) => Ok(Some(transfer)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
         Err(e) => Err(Error::from(e)),
     }
 }
+
+/// Returns the pending outgoing transfer of `repo`, if any.
+pub fn outgoing_transfer(pool: &Pool, repo: i64) -> Result<Option<RepoTransfer>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT repo_transfers.id, repo_transfers.repo, owners.username, repos.name,
+                                               repo_transfers.new_owner, new_owners.username
+                                        FROM repo_transfers
+                                        JOIN repos ON repos.id = repo_transfers.repo
+                                        JOIN users AS owners ON owners.id = repos.owner
+                                        JOIN users AS new_owners ON new_owners.id = repo_transfers.new_owner
+                                        WHERE repo_transfers.repo = ?1"))?;
+    match stmt.query_row(params![repo], repo_transfer_row) {
+        Ok(transfer) => Ok(Some(transfer)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Lists the transfers `user` can accept, those to themselves and to the
+/// organizations they own.
+pub fn incoming_transfers(pool: &Pool, user: i32) -> Result<Vec<RepoTransfer>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT repo_transfers.id, repo_transfers.repo, owners.username, repos.name,
+                                               repo_transfers.new_owner, new_owners.username
+                                        FROM repo_transfers
+                                        JOIN repos ON repos.id = repo_transfers.repo
+                                        JOIN users AS owners ON owners.id = repos.owner
+                                        JOIN users AS new_owners ON new_owners.id = repo_transfers.new_owner
+                                        WHERE repo_transfers.new_owner = ?1
+                                        OR repo_transfers.new_owner IN (SELECT org FROM org_members
+                                                                        WHERE member = ?1 AND is_owner = true)
+                                        ORDER BY repo_transfers.created"))?;
+    let rows = stmt.query_map(params![user], repo_transfer_row)?;
+    let mut transfers = Vec::new();
+    for r in rows {
+        transfers.push(r?);
+    }
+    Ok(transfers)
+}
+
+/// Resolves the current location of a repo that used to live at
+/// `username/reponame` before being transferred. Repos that exist at that
+/// location take precedence over redirects.
+pub fn repo_redirect(pool: &Pool, username: &str, reponame: &str) -> Result<Option<(String, String)>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT users.username, repos.name FROM repo_redirects
+                                        JOIN repos ON repos.id = repo_redirects.repo
+                                        JOIN users ON users.id = repos.owner
+                                        WHERE repo_redirects.owner = (SELECT id FROM users WHERE username = ?1)
+                                        AND repo_redirects.name = ?2
+                                        AND NOT EXISTS (SELECT 1 FROM repos AS existing
+                                                        WHERE existing.owner = repo_redirects.owner
+                                                        AND existing.name = repo_redirects.name)"))?;
+    match stmt.query_row(params![username, reponame], |row| Ok((row.get(0)?, row.get(1)?))) {
+        Ok(repo) => Ok(Some(repo)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+pub fn collaborators(pool: &Pool, repo: i64) -> Result<Vec<Collaborator>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT users.username, collaborators.access FROM collaborators
+                                        JOIN users ON users.id = collaborators.member
+                                        WHERE collaborators.repo = ?1 ORDER BY users.username"))?;
+    let rows = stmt.query_map(params![repo], |row| {
+        Ok(Collaborator {
+            username: row.get(0)?,
+            access: row.get(1)?,
+        })
+    })?;
+    let mut collaborators = Vec::new();
+    for r in rows {
+        collaborators.push(r?);
+    }
+    Ok(collaborators)
+}
+
+/// Returns the owner name and repo name of every repo.
+pub fn all_repos(pool: &Pool) -> Result<Vec<(String, String)>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT users.username, repos.name FROM repos JOIN users ON users.id = repos.owner"))?;
+    let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let mut repos = Vec::new();
+    for r in rows {
+        repos.push(r?);
+    }
+    Ok(repos)
+}
+
+pub fn branch_rules(pool: &Pool, repo: i64) -> Result<Vec<BranchRule>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id, pattern, no_force_push, no_deletion, require_signed
+                                        FROM protected_branches WHERE repo = ?1 ORDER BY pattern"))?;
+    let rows = stmt.query_map(params![repo], |row| {
+        Ok(BranchRule {
+            id: row.get(0)?,
+            pattern: row.get(1)?,
+            no_force_push: row.get(2)?,
+            no_deletion: row.get(3)?,
+            require_signed: row.get(4)?,
+            users: Vec::new(),
+            teams: Vec::new(),
+        })
+    })?;
+    let mut rules = Vec::new();
+    for r in rows {
+        rules.push(r?);
+    }
+
+    let mut users = conn.prepare(query!("SELECT users.username FROM protected_branch_pushers
+                                         JOIN users ON users.id = protected_branch_pushers.member
+                                         WHERE protected_branch_pushers.rule = ?1"))?;
+    let mut teams = conn.prepare(query!("SELECT teams.name FROM protected_branch_pushers
+                                         JOIN teams ON teams.id = protected_branch_pushers.team
+                                         WHERE protected_branch_pushers.rule = ?1"))?;
+    for rule in &mut rules {
+        for r in users.query_map(params![rule.id], |row| row.get(0))? {
+            rule.users.push(r?);
+        }
+        for r in teams.query_map(params![rule.id], |row| row.get(0))? {
+            rule.teams.push(r?);
+        }
+    }
+    Ok(rules)
+}
+
+/// Checks whether `user` is listed as a pusher on a restricted branch rule,
+/// either directly or through a team.
+pub fn branch_rule_allows(pool: &Pool, rule: i32, user: i32) -> Result<bool> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT COUNT(*) FROM protected_branch_pushers
+                                        LEFT JOIN team_members ON team_members.team = protected_branch_pushers.team
+                                        WHERE protected_branch_pushers.rule = ?1
+                                        AND (protected_branch_pushers.member = ?2 OR team_members.member = ?2)"))?;
+    let count: i64 = stmt.query_row(params![rule, user], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+/// Resolves the permission a viewer has on the repo `owner/reponame`, taking
+/// the visibility of the repo into account. `user` is `None` for anonymous
+/// viewers.
+pub fn access(pool: &Pool, user: Option<i32>, owner: &str, reponame: &str) -> Result<Access> {
+    let granted = match user {
+        Some(user) => repo_access(pool, user, owner, reponame)?,
+        None => Access::None,
+    };
+
+    let visible = match repo_visibility(pool, owner, reponame)? {
+        Visibility::Public => true,
+        Visibility::Internal => user.is_some(),
+        Visibility::Private => false,
+    };
+
+    if visible {
+        Ok(::std::cmp::max(granted, Access::Read))
+    } else {
+        Ok(granted)
+    }
+}
+
+/// Resolves the permission `user` has on the repo `owner/reponame` from
+/// ownership, organization roles, direct grants and team grants. This does
+/// not take the visibility of the repo into account.
+pub fn repo_access(pool: &Pool, user: i32, owner: &str, reponame: &str) -> Result<Access> {
+    let repo = match repo_id(pool, owner, reponame)? {
+        Some(id) => id,
+        None => return Ok(Access::None),
+    };
+
+    let owner = user_id(pool, owner)?;
+    if owner == user {
+        return Ok(Access::Admin);
+    }
+
+    let mut access = match org_role(pool, owner, user)? {
+        Some(true) => Access::Admin,
+        Some(false) => Access::Read,
+        None => Access::None,
+    };
+
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT access FROM collaborators WHERE repo = ?1 AND member = ?2"))?;
+    match stmt.query_row(params![repo, user], |row| row.get(0)) {
+        Ok(direct) => access = ::std::cmp::max(access, direct),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {},
+        Err(e) => return Err(Error::from(e)),
+    }
+
+    let mut stmt = conn.prepare(query!("SELECT MAX(teams.access) FROM teams
+                                        JOIN team_members ON team_members.team = teams.id
+                                        JOIN team_repos ON team_repos.team = teams.id
+                                        WHERE team_members.member = ?2 AND team_repos.repo = ?1"))?;
+    let team: Option<Access> = stmt.query_row(params![repo, user], |row| row.get(0))?;
+    if let Some(team) = team {
+        access = ::std::cmp::max(access, team);
+    }
+
+    Ok(access)
+}
+
+pub fn site_setting(pool: &Pool, name: &str) -> Result<Option<String>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT value FROM site_settings WHERE name = ?1"))?;
+    match stmt.query_row(params![name], |row| row.get(0)) {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+pub fn is_ldap_user(pool: &Pool, username: &str) -> Result<bool> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT count(*) FROM users WHERE username = ?1 AND ldap_dn IS NOT NULL"))?;
+    let count: i64 = stmt.query_row(params![username], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+/// Returns the user linked to the LDAP entry `dn`. DNs are compared case
+/// insensitively, like the directory does.
+pub fn user_by_ldap_dn(pool: &Pool, dn: &str) -> Result<Option<i32>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id FROM users WHERE lower(ldap_dn) = lower(?1)"))?;
+    match stmt.query_row(params![dn], |row| row.get(0)) {
+        Ok(id) => Ok(Some(id)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Returns the id, name and DN of every enabled user from the LDAP directory.
+pub fn ldap_users(pool: &Pool) -> Result<Vec<(i32, String, String)>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id, username, ldap_dn FROM users WHERE ldap_dn IS NOT NULL AND disabled = false"))?;
+    let rows = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    let mut users = Vec::new();
+    for r in rows {
+        users.push(r?);
+    }
+    Ok(users)
+}
+
+pub fn user_disabled(pool: &Pool, id: i32) -> Result<Option<bool>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT disabled FROM users WHERE id = ?1"))?;
+    match stmt.query_row(params![id], |row| row.get(0)) {
+        Ok(disabled) => Ok(Some(disabled)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+pub fn admin_users(pool: &Pool) -> Result<Vec<AdminUser>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id, username, email, num_repos, is_admin, is_org, disabled,
+                                        ldap_dn IS NOT NULL FROM users ORDER BY username"))?;
+    let rows = stmt.query_map(params![], |row| {
+        Ok(AdminUser {
+            id: row.get(0)?,
+            username: row.get(1)?,
+            email: row.get(2)?,
+            num_repos: row.get(3)?,
+            is_admin: row.get(4)?,
+            is_org: row.get(5)?,
+            disabled: row.get(6)?,
+            ldap: row.get(7)?,
+        })
+    })?;
+    let mut users = Vec::new();
+    for r in rows {
+        users.push(r?);
+    }
+    Ok(users)
+}
+
+pub fn admin_repos(pool: &Pool) -> Result<Vec<AdminRepo>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT repos.id, users.username, repos.name, repos.visibility FROM repos
+                                        JOIN users ON users.id = repos.owner
+                                        ORDER BY users.username, repos.name"))?;
+    let rows = stmt.query_map(params![], |row| {
+        Ok(AdminRepo {
+            id: row.get(0)?,
+            owner: row.get(1)?,
+            name: row.get(2)?,
+            visibility: row.get(3)?,
+        })
+    })?;
+    let mut repos = Vec::new();
+    for r in rows {
+        repos.push(r?);
+    }
+    Ok(repos)
+}
+
+/// Returns the owner name and repo name of the repo with the given id.
+pub fn repo_by_id(pool: &Pool, id: i64) -> Result<Option<(String, String)>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT users.username, repos.name FROM repos
+                                        JOIN users ON users.id = repos.owner WHERE repos.id = ?1"))?;
+    match stmt.query_row(params![id], |row| Ok((row.get(0)?, row.get(1)?))) {
+        Ok(repo) => Ok(Some(repo)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+pub fn public_key_ids(pool: &Pool, owner: i32) -> Result<Vec<i32>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id FROM public_keys WHERE owner = ?1"))?;
+    let rows = stmt.query_map(params![owner], |row| row.get(0))?;
+    let mut keys = Vec::new();
+    for r in rows {
+        keys.push(r?);
+    }
+    Ok(keys)
+}
+
+/// Lists invitations, optionally only those created by `created_by`.
+pub fn invites(pool: &Pool, created_by: Option<i32>) -> Result<Vec<Invite>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT invites.id, invites.code, creators.username, invites.expires, used.username
+                                        FROM invites
+                                        JOIN users AS creators ON creators.id = invites.created_by
+                                        LEFT JOIN users AS used ON used.id = invites.used_by
+                                        WHERE ?1 IS NULL OR invites.created_by = ?1
+                                        ORDER BY invites.expires DESC"))?;
+    let rows = stmt.query_map(params![created_by], |row| {
+        Ok(Invite {
+            id: row.get(0)?,
+            code: row.get(1)?,
+            created_by: row.get(2)?,
+            expires: row.get(3)?,
+            used_by: row.get(4)?,
+        })
+    })?;
+    let mut invites = Vec::new();
+    for r in rows {
+        invites.push(r?);
+    }
+    Ok(invites)
+}
+
+/// Checks that an invitation exists, is unused and has not expired.
+pub fn invite_valid(pool: &Pool, code: &str) -> Result<bool> {
+    let conn = pool.get()?;
+    let now = ::chrono::Utc::now().naive_utc();
+    let mut stmt = conn.prepare(query!("SELECT count(*) FROM invites WHERE code = ?1 AND used_by IS NULL AND expires > ?2"))?;
+    let count: i64 = stmt.query_row(params![code, now], |row| row.get(0))?;
+    Ok(count > 0)
+}
+
+pub fn invite_creator(pool: &Pool, id: i32) -> Result<Option<i32>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT created_by FROM invites WHERE id = ?1"))?;
+    match stmt.query_row(params![id], |row| row.get(0)) {
+        Ok(user) => Ok(Some(user)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+pub fn throttle(pool: &Pool, kind: &str, key: &str) -> Result<Option<Throttle>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id, kind, key, failures, last_failure, locked_until FROM login_throttles
+                                        WHERE kind = ?1 AND key = ?2"))?;
+    match stmt.query_row(params![kind, key], |row| {
+        Ok(Throttle {
+            id: row.get(0)?,
+            kind: row.get(1)?,
+            key: row.get(2)?,
+            failures: row.get(3)?,
+            last_failure: row.get(4)?,
+            locked_until: row.get(5)?,
+        })
+    }) {
+        Ok(throttle) => Ok(Some(throttle)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Lists the accounts and addresses that are currently locked out.
+pub fn lockouts(pool: &Pool) -> Result<Vec<Throttle>> {
+    let conn = pool.get()?;
+    let now = ::chrono::Utc::now().naive_utc();
+    let mut stmt = conn.prepare(query!("SELECT id, kind, key, failures, last_failure, locked_until FROM login_throttles
+                                        WHERE locked_until > ?1 ORDER BY locked_until DESC"))?;
+    let rows = stmt.query_map(params![now], |row| {
+        Ok(Throttle {
+            id: row.get(0)?,
+            kind: row.get(1)?,
+            key: row.get(2)?,
+            failures: row.get(3)?,
+            last_failure: row.get(4)?,
+            locked_until: row.get(5)?,
+        })
+    })?;
+    let mut lockouts = Vec::new();
+    for r in rows {
+        lockouts.push(r?);
+    }
+    Ok(lockouts)
+}
+
+/// Returns the most recent authentication events, newest first.
+pub fn auth_events(pool: &Pool, limit: i64) -> Result<Vec<AuthEvent>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT created, event, username, ip, source FROM auth_events
+                                        ORDER BY created DESC LIMIT ?1"))?;
+    let rows = stmt.query_map(params![limit], |row| {
+        Ok(AuthEvent {
+            created: row.get(0)?,
+            event: row.get(1)?,
+            username: row.get(2)?,
+            ip: row.get(3)?,
+            source: row.get(4)?,
+        })
+    })?;
+    let mut events = Vec::new();
+    for r in rows {
+        events.push(r?);
+    }
+    Ok(events)
+}
+
+/// Returns the TOTP secret of `user` and the last time step used to log in,
+/// or `None` if they haven't enabled two-factor authentication.
+pub fn totp(pool: &Pool, user: i32) -> Result<Option<(String, Option<i64>)>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT totp_secret, totp_last_step FROM users WHERE id = ?1"))?;
+    let (secret, last_step): (Option<String>, Option<i64>) =
+        stmt.query_row(params![user], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    Ok(secret.map(|secret| (secret, last_step)))
+}
+
+pub fn recovery_codes_left(pool: &Pool, user: i32) -> Result<i64> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT count(*) FROM recovery_codes WHERE owner = ?1"))?;
+    Ok(stmt.query_row(params![user], |row| row.get(0))?)
+}
+
+/// Returns the user linked to the OpenID Connect identity `subject` at `issuer`.
+pub fn oidc_user(pool: &Pool, issuer: &str, subject: &str) -> Result<Option<i32>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT user FROM oidc_identities WHERE issuer = ?1 AND subject = ?2"))?;
+    match stmt.query_row(params![issuer, subject], |row| row.get(0)) {
+        Ok(user) => Ok(Some(user)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Returns the user with the email address `email`, if exactly one user has it.
+pub fn user_by_email(pool: &Pool, email: &str) -> Result<Option<i32>> {
+    let conn = pool.get()?;
+    let mut stmt = conn.prepare(query!("SELECT id FROM users WHERE lower(email) = lower(?1) AND is_org = false"))?;
+    let rows = stmt.query_map(params![email], |row| row.get(0))?;
+    let mut users = Vec::new();
+    for r in rows {
+        users.push(r?);
+    }
+    if users.len() == 1 {
+        Ok(users.pop())
+    } else {
+        Ok(None)
+    }
+}
+
+/// Returns the user logged in with the session `id`, if the session hasn't
+/// expired or been revoked and the user is still enabled.
+pub fn session_user(pool: &Pool, id: &str) -> Result<Option<CurrentUser>> {
+    let conn = pool.get()?;
+    let now = ::chrono::Utc::now().naive_utc();
+    let mut stmt = conn.prepare(query!("SELECT users.id, users.username, users.is_admin FROM user_sessions
+                                        JOIN users ON users.id = user_sessions.user
+                                        WHERE user_sessions.id = ?1 AND user_sessions.expires > ?2
+                                        AND users.disabled = false"))?;
+    let row = stmt.query_row(params![id, now], |row| {
+        Ok(CurrentUser {
+            id: row.get(0)?,
+            username: row.get(1)?,
+            is_admin: row.get(2)?,
+        })
+    });
+    match row {
+        Ok(user) => Ok(Some(user)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(Error::from(e)),
+    }
+}
+
+/// Lists the sessions of `user`, most recently used first.
+pub fn sessions(pool: &Pool, user: i32) -> Result<Vec<UserSession>> {
+    let conn = pool.get()?;
+    let now = ::chrono::Utc::now().naive_utc();
+    let mut stmt = conn.prepare(query!("SELECT id, created, last_seen, user_agent, ip FROM user_sessions
+                                        WHERE user = ?1 AND expires > ?2 ORDER BY last_seen DESC"))?;
+    let rows = stmt.query_map(params![user, now], |row| {
+        Ok(UserSession {
+            id: row.get(0)?,
+            created: row.get(1)?,
+            last_seen: row.get(2)?,
+            user_agent: row.get(3)?,
+            ip: row.get(4)?,
+            current: false,
+        })
+    })?;
+    let mut sessions = Vec::new();
+    for r in rows {
+        sessions.push(r?);
+    }
+    Ok(sessions)
+}
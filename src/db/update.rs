@@ -1,6 +1,8 @@
 use Result;
 use super::Pool;
 
+use chrono::NaiveDateTime;
+
 pub fn repo_name(pool: &Pool, username: &str, old_name: &str, new_name: &str) -> Result<()> {
     let repo = super::read::repo_id(pool, username, old_name)?.unwrap();
     let conn = pool.get()?;
@@ -17,3 +19,104 @@ pub fn repo(pool: &Pool, username: &str, reponame: &str) -> Result<()> {
                  params![now, repo])?;
     Ok(())
 }
+
+pub fn site_setting(pool: &Pool, name: &str, value: &str) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("INSERT INTO site_settings (name, value) VALUES (?1, ?2)
+                         ON CONFLICT (name) DO UPDATE SET value = ?2"),
+                 params![name, value])?;
+    Ok(())
+}
+
+pub fn user_disabled(pool: &Pool, id: i32, disabled: bool) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("UPDATE users SET disabled = ?1 WHERE id = ?2"), params![disabled, id])?;
+    Ok(())
+}
+
+pub fn user_admin(pool: &Pool, id: i32, is_admin: bool) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("UPDATE users SET is_admin = ?1 WHERE id = ?2"), params![is_admin, id])?;
+    Ok(())
+}
+
+/// Marks a user as coming from the LDAP directory, so that they are kept in
+/// sync with it.
+pub fn ldap_dn(pool: &Pool, id: i32, dn: &str) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("UPDATE users SET ldap_dn = ?1 WHERE id = ?2"), params![dn, id])?;
+    Ok(())
+}
+
+/// Moves a repo to a new owner, keeping both owners' repo counts in sync and
+/// leaving a redirect at the old location. Team grants belong to the old
+/// owner and are dropped.
+pub fn transfer_repo(pool: &Pool, repo: i64, new_owner: i32) -> Result<()> {
+    let mut conn = pool.get()?;
+    let tx = conn.transaction()?;
+    let (old_owner, name): (i32, String) = tx.query_row(query!("SELECT owner, name FROM repos WHERE id = ?1"),
+                                                        params![repo], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    tx.execute(query!("UPDATE repos SET owner = ?1 WHERE id = ?2"), params![new_owner, repo])?;
+    tx.execute(query!("DELETE FROM repo_transfers WHERE repo = ?1"), params![repo])?;
+    tx.execute(query!("DELETE FROM repo_redirects WHERE owner = ?1 AND name = ?2"), params![new_owner, name])?;
+    tx.execute(query!("INSERT INTO repo_redirects (owner, name, repo) VALUES (?1, ?2, ?3)
+                       ON CONFLICT (owner, name) DO UPDATE SET repo = ?3"),
+               params![old_owner, name, repo])?;
+    tx.execute(query!("UPDATE users SET num_repos = num_repos - 1 WHERE id = ?1"), params![old_owner])?;
+    tx.execute(query!("UPDATE users SET num_repos = num_repos + 1 WHERE id = ?1"), params![new_owner])?;
+    tx.execute(query!("DELETE FROM team_repos WHERE repo = ?1"), params![repo])?;
+    tx.execute(query!("DELETE FROM protected_branch_pushers WHERE team IS NOT NULL
+                       AND rule IN (SELECT id FROM protected_branches WHERE repo = ?1)"), params![repo])?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub fn throttle(pool: &Pool, kind: &str, key: &str, failures: i64, last_failure: NaiveDateTime,
+                locked_until: Option<NaiveDateTime>) -> Result<()>
+{
+    let conn = pool.get()?;
+    conn.execute(query!("INSERT INTO login_throttles (kind, key, failures, last_failure, locked_until)
+                         VALUES (?1, ?2, ?3, ?4, ?5)
+                         ON CONFLICT (kind, key) DO UPDATE SET failures = ?3, last_failure = ?4, locked_until = ?5"),
+                 params![kind, key, failures, last_failure, locked_until])?;
+    Ok(())
+}
+
+/// Enables two-factor authentication with `secret`, or disables it if `None`.
+pub fn totp(pool: &Pool, user: i32, secret: Option<&str>, last_step: Option<i64>) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("UPDATE users SET totp_secret = ?1, totp_last_step = ?2 WHERE id = ?3"),
+                 params![secret, last_step, user])?;
+    Ok(())
+}
+
+/// Records the last TOTP time step used so that codes can't be replayed.
+pub fn totp_last_step(pool: &Pool, user: i32, step: i64) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("UPDATE users SET totp_last_step = ?1 WHERE id = ?2"), params![step, user])?;
+    Ok(())
+}
+
+pub fn password(pool: &Pool, user: i32, password: &str) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("UPDATE users SET password = ?1 WHERE id = ?2"), params![password, user])?;
+    Ok(())
+}
+
+pub fn email(pool: &Pool, user: i32, email: &str) -> Result<()> {
+    let conn = pool.get()?;
+    conn.execute(query!("UPDATE users SET email = ?1 WHERE id = ?2"), params![email, user])?;
+    Ok(())
+}
+
+/// Marks a session as seen now and pushes back its expiry, so that sessions
+/// only expire after `lifetime` without use. The row is only written once a
+/// minute to avoid a write on every request.
+pub fn session_seen(pool: &Pool, id: &str, lifetime: ::chrono::Duration) -> Result<()> {
+    let conn = pool.get()?;
+    let now = ::chrono::Utc::now().naive_utc();
+    let stale = now - ::chrono::Duration::minutes(1);
+    conn.execute(query!("UPDATE user_sessions SET last_seen = ?1, expires = ?2 WHERE id = ?3 AND last_seen < ?4"),
+                 params![now, now + lifetime, id, stale])?;
+    Ok(())
+}
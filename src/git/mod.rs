@@ -1,16 +1,39 @@
 pub mod network;
+pub mod signing;
 mod util;
 
-use {Context, Result};
-use templates::{CommitTmpl, RefsTmpl, RepoTmpl};
+use {Context, Error, Result};
+use templates::{BlameTmpl, CommitTmpl, CompareTmpl, RefsTmpl, RepoTmpl};
 use types::*;
 use self::util::*;
 
 use git2::{self, ObjectType, Repository};
 
+use std::cmp::{self, Reverse};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{Read, Write};
-use std::path::{Path};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many commits the log and history pages show at once.
+const LOG_PAGE_SIZE: usize = 100;
+/// The commit count cache is emptied when it grows past this many commits.
+const MAX_COUNTED_COMMITS: usize = 1000;
+/// Comparisons stop counting commits after this many.
+const MAX_COMPARE_COUNT: usize = 1000;
+/// How many commits can wait to be counted, further ones are dropped.
+const COUNT_QUEUE_SIZE: usize = 16;
+/// How many branches and tags the refs page shows at once.
+const REFS_PAGE_SIZE: usize = 50;
+/// How many refs the commit page checks for containing the commit.
+const MAX_CONTAINING_REFS: usize = 25;
+/// Bigger files aren't blamed.
+const MAX_BLAME_BYTES: usize = 512 * 1024;
+/// How long the measured disk usage is reused before measuring again.
+const DISK_USAGE_SECS: u64 = 300;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum AccessMode {
@@ -58,12 +81,22 @@ no-port-forwarding,no-X11-forwarding,no-agent-forwarding,no-pty {}",
 }
 
 pub fn delete_ssh_key(ctx: &Context, id: i32) -> Result<()> {
+    delete_ssh_keys(ctx, &[id])
+}
+
+/// Removes the given keys from `authorized_keys` in a single rewrite.
+pub fn delete_ssh_keys(ctx: &Context, ids: &[i32]) -> Result<()> {
     let mut ssh_dir = ctx.ssh_dir.clone();
     ssh_dir.push("authorized_keys");
     let mut file = fs::File::open(&ssh_dir)?;
     let mut buf = String::new();
     file.read_to_string(&mut buf)?;
-    let buf = buf.lines().filter(|l| !l.contains(&format!("ssh key-{}", id))).collect::<String>();
+    // Match the closing quote of the command so that key-1 doesn't also match key-10
+    let patterns: Vec<String> = ids.iter().map(|id| format!("ssh key-{}\"", id)).collect();
+    let buf: String = buf.lines()
+        .filter(|l| !patterns.iter().any(|p| l.contains(p.as_str())))
+        .map(|l| format!("{}\n", l))
+        .collect();
     let mut file = fs::OpenOptions::new()
         .write(true)
         .truncate(true)
@@ -74,6 +107,25 @@ pub fn delete_ssh_key(ctx: &Context, id: i32) -> Result<()> {
 pub fn init(ctx: &Context, username: &str, reponame: &str) -> Result<()> {
     let path = build_repo_path(ctx, username, reponame);
     Repository::init_bare(path)?;
+    install_hooks(ctx, username, reponame)
+}
+
+/// Installs the Valentine managed hooks into a repository, replacing any
+/// existing ones. The hooks call back into this binary so that repository
+/// rules are enforced no matter which transport a push arrives through.
+pub fn install_hooks(ctx: &Context, username: &str, reponame: &str) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut path = build_repo_path(ctx, username, reponame);
+    path.push("hooks");
+    fs::create_dir_all(&path)?;
+    path.push("pre-receive");
+
+    let hook = format!("#!/bin/sh\n# Managed by Valentine, do not edit.\n\
+exec {} -c {} hook pre-receive \"$VALENTINE_KEY_ID\"\n",
+                       shell_quote(&ctx.bin_path), shell_quote(&ctx.config_path));
+    fs::write(&path, hook)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
     Ok(())
 }
 
@@ -84,6 +136,44 @@ pub fn mov(ctx: &Context, username: &str, old_name: &str, new_name: &str) -> Res
     Ok(())
 }
 
+/// Moves a repo between owner directories.
+pub fn transfer(ctx: &Context, old_owner: &str, new_owner: &str, repo_name: &str) -> Result<()> {
+    let old_path = build_repo_path(ctx, old_owner, repo_name);
+    let new_path = build_repo_path(ctx, new_owner, repo_name);
+    fs::create_dir_all(ctx.repo_dir.join(new_owner))?;
+    fs::rename(old_path, new_path)?;
+    Ok(())
+}
+
+/// Returns the total size in bytes of everything under `repo_dir`. Walking
+/// every repo is slow, so the size is measured at most every few minutes.
+pub fn disk_usage(ctx: &Context) -> Result<u64> {
+    fn dir_size(path: &Path) -> Result<u64> {
+        let mut size = 0;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                size += dir_size(&entry.path())?;
+            } else {
+                size += metadata.len();
+            }
+        }
+        Ok(size)
+    }
+
+    let mut cached = ctx.disk_usage.lock().unwrap();
+    if let Some((measured, size)) = *cached {
+        if measured.elapsed() < Duration::from_secs(DISK_USAGE_SECS) {
+            return Ok(size);
+        }
+    }
+
+    let size = dir_size(&ctx.repo_dir)?;
+    *cached = Some((Instant::now(), size));
+    Ok(size)
+}
+
 pub fn delete(ctx: &Context, username: &str, repo_name: &str) -> Result<()> {
     let path = build_repo_path(ctx, username, &repo_name);
     fs::remove_dir_all(path)?;
@@ -165,32 +255,75 @@ pub fn read<'a, 'b>(ctx: &'a Context, username: &'b str, repo_info: Repo)
     Ok(tmpl)
 }
 
-pub fn refs<'a, 'b>(ctx: &'a Context, username: &'b str, repo_info: Repo)
+/// Lists a page of the branches and tags whose names contain `filter`,
+/// sorted by name with branches first or by date with the newest first.
+pub fn refs<'a, 'b>(ctx: &'a Context, username: &'b str, repo_info: Repo, page: usize,
+                    by_date: bool, filter: String)
     -> Result<RefsTmpl<'a, 'b>>
 {
     let path = build_repo_path(ctx, username, &repo_info.name);
     let repo = Repository::open(path)?;
-    let branches_raw: Vec<_> = repo.branches(None)?.take(5).collect();
-    let mut branches = Vec::new();
-    for branch in branches_raw {
-        if let Some(name) = branch?.0.name()? {
-            branches.push(Branch { name: name.to_string() });
+    // Empty repos and detached heads have no default branch
+    let default = match repo.head() {
+        Ok(ref head) if head.is_branch() => {
+            head.shorthand().map(|name| (name.to_string(), head.target()))
         }
-    }
+        _ => None,
+    };
 
-    let mut tags = Vec::new();
-    for tag in repo.tag_names(None)?.iter() {
-        if let Some(name) = tag {
-            tags.push(Tag { name: name.to_string() });
+    let lower_filter = filter.to_lowercase();
+    let mut refs = Vec::new();
+    for reference in repo.references()? {
+        let reference = reference?;
+        let tag = reference.is_tag();
+        if !tag && !reference.is_branch() {
+            continue;
+        }
+        let name = match reference.shorthand() {
+            Some(name) if name.to_lowercase().contains(&lower_filter) => name.to_string(),
+            _ => continue,
+        };
+        if let Ok(commit) = reference.peel_to_commit() {
+            refs.push((name, tag, commit));
         }
     }
+    if by_date {
+        refs.sort_by_key(|r| Reverse(r.2.time().seconds()));
+    } else {
+        refs.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    }
+
+    let pages = cmp::max(1, (refs.len() + REFS_PAGE_SIZE - 1) / REFS_PAGE_SIZE);
+    let page = cmp::min(cmp::max(page, 1), pages);
+    let mut infos = Vec::new();
+    let shown = refs.into_iter().skip((page - 1) * REFS_PAGE_SIZE).take(REFS_PAGE_SIZE);
+    for (name, tag, commit) in shown {
+        let is_default = !tag && default.as_ref().map_or(false, |d| d.0 == name);
+        let ahead_behind = match default {
+            Some((_, Some(oid))) if !is_default => Some(repo.graph_ahead_behind(commit.id(), oid)?),
+            _ => None,
+        };
+        infos.push(RefInfo {
+            name: name,
+            tag: tag,
+            is_default: is_default,
+            commit: Commit::new(&commit)?,
+            ahead_behind: ahead_behind,
+        });
+    }
 
     Ok(RefsTmpl {
         mount: &ctx.mount,
         username: username,
         repo: repo_info,
-        branches: branches,
-        tags: tags,
+        default_branch: default.map(|d| d.0).unwrap_or_default(),
+        refs: infos,
+        by_date: by_date,
+        filter: filter,
+        prev: if page > 1 { Some(page - 1) } else { None },
+        next: if page < pages { Some(page + 1) } else { None },
+        page: page,
+        pages: pages,
     })
 }
 
@@ -198,7 +331,8 @@ pub fn read_src<'a, 'b>(ctx: &'a Context,
                         username: &'b str,
                         repo_info: &Repo,
                         id: &str,
-                        file: &str)
+                        file: &str,
+                        highlight: bool)
     -> Result<Option<RepoSrc>>
 {
     let path = build_repo_path(ctx, username, &repo_info.name);
@@ -227,10 +361,20 @@ pub fn read_src<'a, 'b>(ctx: &'a Context,
             match read_file(&repo, &entry)? {
                 Some(c) => {
                     let size = format_size(c.len(), DECIMAL);
+                    let highlighted = if highlight {
+                        let language = linguist_language(&repo, &tree, file)?;
+                        let id = entry.id().to_string();
+                        ctx.highlighter.highlight(&id, file, &c, language.as_ref().map(|l| l.as_str()))
+                            .map_or(Vec::new(), |lines| {
+                                lines.iter().enumerate().map(|(i, s)| (i+1, s.clone())).collect()
+                            })
+                    } else {
+                        Vec::new()
+                    };
                     let file = c.lines().enumerate()
                         .map(|(i, s)| (i+1, s.to_string()))
                         .collect::<Vec<_>>();
-                    Ok(Some(RepoSrc::File { file, size }))
+                    Ok(Some(RepoSrc::File { file, size, highlighted }))
                 }
                 None => Ok(Some(RepoSrc::Error)),
             }
@@ -243,47 +387,301 @@ pub fn read_src<'a, 'b>(ctx: &'a Context,
     }
 }
 
-pub fn log(ctx: &Context, username: &str, reponame: &str, id: &str)
-    -> Result<Option<(Vec<Commit>, usize, Option<String>)>>
+pub fn blame<'a, 'b>(ctx: &'a Context, username: &'b str, repo_info: Repo, id: &'b str,
+                     file: &'b str)
+    -> Result<Option<BlameTmpl<'a, 'b>>>
 {
-    let path = build_repo_path(ctx, username, reponame);
+    let path = build_repo_path(ctx, username, &repo_info.name);
     let repo = Repository::open(path)?;
-    let oid = if let Ok(oid) = git2::Oid::from_str(id) {
-        oid
-    } else {
-        let reference = match get_ref(&repo, id)? {
-            Some(r) => r,
-            _ => return Ok(None),
-        };
+    let commit = match get_commit(&repo, id)? {
+        Some(r) => r,
+        _ => return Ok(None),
+    };
+    let tree = commit.tree()?;
+    let entry = catch_git!(tree.get_path(Path::new(file)), git2::ErrorCode::NotFound, None);
+    if entry.kind() != Some(ObjectType::Blob) {
+        return Ok(None);
+    }
+    // The size is read from the header so that big files are never loaded
+    let (size, _) = repo.odb()?.read_header(entry.id())?;
+    if size > MAX_BLAME_BYTES {
+        return Ok(Some(BlameTmpl {
+            mount: &ctx.mount,
+            username: username,
+            repo: repo_info,
+            refname: id,
+            filename: file,
+            too_large: true,
+            groups: Vec::new(),
+        }));
+    }
+    let content = match read_file(&repo, &entry)? {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+
+    let mut options = git2::BlameOptions::new();
+    options.newest_commit(commit.id());
+    let blame = repo.blame_file(Path::new(file), Some(&mut options))?;
+
+    let mut lines = content.lines();
+    let mut commits = HashMap::new();
+    let mut groups: Vec<BlameGroup> = Vec::new();
+    for hunk in blame.iter() {
+        let start = hunk.final_start_line();
+        let hunk_lines = lines.by_ref().take(hunk.lines_in_hunk()).enumerate()
+            .map(|(i, line)| (start + i, line.to_string()));
 
-        match reference.target() {
-            Some(o) => o,
-            _ => return Ok(None),
+        // Hunks next to each other can come from the same commit
+        let oid = hunk.final_commit_id();
+        if let Some(group) = groups.last_mut() {
+            if group.commit.id == oid.to_string() {
+                group.lines.extend(hunk_lines);
+                continue;
+            }
         }
+
+        if !commits.contains_key(&oid) {
+            let raw = repo.find_commit(oid)?;
+            let parent = raw.parent_ids().next().map(|p| p.to_string());
+            commits.insert(oid, (Commit::new(&raw)?, parent));
+        }
+        let (ref blamed, ref parent) = commits[&oid];
+        groups.push(BlameGroup {
+            commit: blamed.clone(),
+            path: hunk.path().map_or(file.to_string(), |p| p.to_string_lossy().into_owned()),
+            parent: parent.clone(),
+            lines: hunk_lines.collect(),
+        });
+    }
+
+    Ok(Some(BlameTmpl {
+        mount: &ctx.mount,
+        username: username,
+        repo: repo_info,
+        refname: id,
+        filename: file,
+        too_large: false,
+        groups: groups,
+    }))
+}
+
+/// Compares `head` to `base`, showing what `head` adds since they diverged
+/// like `git diff base...head`.
+pub fn compare<'a, 'b>(ctx: &'a Context, username: &'b str, repo_info: Repo, base: &'b str,
+                       head: &'b str)
+    -> Result<Option<CompareTmpl<'a, 'b>>>
+{
+    let path = build_repo_path(ctx, username, &repo_info.name);
+    let repo = Repository::open(path)?;
+    let (base_commit, head_commit) = match (get_commit(&repo, base)?, get_commit(&repo, head)?) {
+        (Some(base), Some(head)) => (base, head),
+        _ => return Ok(None),
+    };
+    let merge_base = match repo.merge_base(base_commit.id(), head_commit.id()) {
+        Ok(oid) => Some(oid),
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => None,
+        Err(e) => return Err(e.into()),
     };
 
-    let mut log = Vec::new();
+    let refs = ref_index(&repo)?;
+    let mut commits = Vec::new();
     let mut revwalk = repo.revwalk()?;
-    revwalk.push(oid)?;
-    let mut i = 0;
-    while let Some(id) = revwalk.next() {
-        if i < 1000 {
-            let id = id?;
-            let commit = repo.find_commit(id)?;
-            let item = Commit::new(&commit)?;
-            log.push(item);
+    revwalk.push(head_commit.id())?;
+    revwalk.hide(base_commit.id())?;
+    let mut total = 0;
+    let mut more = false;
+    for oid in revwalk {
+        let oid = oid?;
+        // Unrelated histories could mean walking everything
+        if total == MAX_COMPARE_COUNT {
+            more = true;
+            break;
+        }
+        if total < LOG_PAGE_SIZE {
+            let mut item = Commit::new(&repo.find_commit(oid)?)?;
+            item.refs = refs.get(&oid).cloned().unwrap_or_default();
+            commits.push(item);
         }
-        i += 1;
+        total += 1;
     }
-    let next_page = if let Some(id) = revwalk.next() {
-        Some(id?.to_string())
-    } else {
-        None
+
+    // Unrelated histories are diffed directly
+    let old_tree = match merge_base {
+        Some(oid) => repo.find_commit(oid)?.tree()?,
+        None => base_commit.tree()?,
+    };
+    let files = diff_trees(&repo, Some(&old_tree), &head_commit.tree()?)?;
+    let merge_base = match merge_base {
+        Some(oid) => Some(Commit::new(&repo.find_commit(oid)?)?),
+        None => None,
+    };
+    let (branches, tags) = list_refs(&repo)?;
+
+    Ok(Some(CompareTmpl {
+        mount: &ctx.mount,
+        username: username,
+        repo: repo_info,
+        base: base,
+        head: head,
+        merge_base: merge_base,
+        commits: commits,
+        total: total,
+        more: more,
+        additions: files.iter().map(|f| f.additions).sum(),
+        deletions: files.iter().map(|f| f.deletions).sum(),
+        files: files,
+        branches: branches,
+        tags: tags,
+    }))
+}
+
+/// Lists the commits from `id` that changed `path`, following it through
+/// renames. Pages work like in `log_page`, and the walk always starts at `id`
+/// so that the path is followed the same way on every page. Returns the
+/// commits and the cursor of the next page.
+pub fn history(ctx: &Context, username: &str, reponame: &str, id: &str, path: &str, page: usize,
+               after: Option<&str>)
+    -> Result<Option<(Vec<Commit>, Option<String>)>>
+{
+    let repo_path = build_repo_path(ctx, username, reponame);
+    let repo = Repository::open(repo_path)?;
+    let start = match get_commit(&repo, id)? {
+        Some(r) => r,
+        _ => return Ok(None),
+    };
+    if path_id(&start.tree()?, path).is_none() {
+        return Ok(None);
+    }
+
+    let mut path = path.to_string();
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(start.id())?;
+    let oids = revwalk.filter_map(|oid| {
+        let oid = match oid {
+            Ok(oid) => oid,
+            Err(e) => return Some(Err(Error::from(e))),
+        };
+        match changes_path(&repo, oid, &mut path) {
+            Ok(true) => Some(Ok(oid)),
+            Ok(false) => None,
+            Err(e) => Some(Err(e)),
+        }
+    });
+    log_page(&repo, oids, page, after)
+}
+
+/// Whether the commit `oid` changed `path`. If the commit renamed it, `path`
+/// is updated to the old name, which older commits have the file under.
+fn changes_path(repo: &Repository, oid: git2::Oid, path: &mut String) -> Result<bool> {
+    let commit = repo.find_commit(oid)?;
+    let tree = commit.tree()?;
+    let entry = path_id(&tree, path);
+
+    // Like git log, merges only count if they differ from every parent
+    let mut renamed_from = None;
+    for parent in commit.parents() {
+        let parent_tree = parent.tree()?;
+        let parent_entry = path_id(&parent_tree, path);
+        if parent_entry == entry {
+            return Ok(false);
+        }
+        if entry.is_some() && parent_entry.is_none() && renamed_from.is_none() {
+            renamed_from = find_rename(repo, &parent_tree, &tree, path)?;
+        }
+    }
+    if commit.parent_count() == 0 && entry.is_none() {
+        return Ok(false);
+    }
+    if let Some(old_path) = renamed_from {
+        *path = old_path;
+    }
+    Ok(true)
+}
+
+/// Reads a page of the log from `id`. Returns the commits, the total number of
+/// commits if it's known yet and the cursor of the next page.
+pub fn log(ctx: &Context, username: &str, reponame: &str, id: &str, page: usize,
+           after: Option<&str>)
+    -> Result<Option<(Vec<Commit>, Option<usize>, Option<String>)>>
+{
+    let path = build_repo_path(ctx, username, reponame);
+    let repo = Repository::open(&path)?;
+    let start = match get_commit(&repo, id)? {
+        Some(r) => r.id(),
+        _ => return Ok(None),
     };
-    Ok(Some((log, i, next_page)))
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(start)?;
+    let oids = revwalk.map(|oid| oid.map_err(Error::from));
+    let (log, next_page) = match log_page(&repo, oids, page, after)? {
+        Some(page) => page,
+        None => return Ok(None),
+    };
+    let total = commit_count(ctx, path, start);
+    Ok(Some((log, total, next_page)))
 }
 
-pub fn commit<'a, 'b>(ctx: &'a Context, username: &'b str, repo_info: Repo, id: &'b str)
+/// Returns the number of commits reachable from `tip` if it was counted
+/// already, otherwise queues it to be counted in the background.
+fn commit_count(ctx: &Context, path: PathBuf, tip: git2::Oid) -> Option<usize> {
+    let key = (path, tip);
+    let mut counts = ctx.commit_counts.lock().unwrap();
+    if let Some(&count) = counts.counts.get(&key) {
+        return Some(count);
+    }
+    // When the queue is full the count is left out until a later request
+    if !counts.queued.contains(&key) && counts.queue.try_send(key.clone()).is_ok() {
+        counts.queued.insert(key);
+    }
+    None
+}
+
+/// Starts the thread that counts commits for the log, returning the cache it
+/// fills. Counting walks the whole history, so it's done one repo at a time.
+pub fn commit_counter() -> Arc<Mutex<CommitCounts>> {
+    let (queue, jobs) = mpsc::sync_channel(COUNT_QUEUE_SIZE);
+    let counts = Arc::new(Mutex::new(CommitCounts {
+        counts: HashMap::new(),
+        queued: HashSet::new(),
+        queue: queue,
+    }));
+
+    let shared = counts.clone();
+    thread::spawn(move || {
+        for (path, tip) in jobs {
+            let count = Repository::open(&path).and_then(|repo| {
+                let mut revwalk = repo.revwalk()?;
+                revwalk.push(tip)?;
+                let mut count = 0;
+                for oid in revwalk {
+                    oid?;
+                    count += 1;
+                }
+                Ok(count)
+            });
+
+            let mut counts = shared.lock().unwrap();
+            let key = (path, tip);
+            counts.queued.remove(&key);
+            match count {
+                Ok(count) => {
+                    // Logs can be viewed from any commit, so this can't grow forever
+                    if counts.counts.len() >= MAX_COUNTED_COMMITS {
+                        counts.counts.clear();
+                    }
+                    counts.counts.insert(key, count);
+                }
+                Err(e) => warn!("unable to count the commits of {}: {}", key.0.display(), e),
+            }
+        }
+    });
+    counts
+}
+
+pub fn commit<'a, 'b>(ctx: &'a Context, username: &'b str, repo_info: Repo, id: &'b str,
+                      parent: usize)
     -> Result<Option<CommitTmpl<'a, 'b>>>
 {
     let path = build_repo_path(ctx, username, &repo_info.name);
@@ -292,11 +690,55 @@ pub fn commit<'a, 'b>(ctx: &'a Context, username: &'b str, repo_info: Repo, id:
         Some(r) => r,
         _ => return Ok(None),
     };
+    // Merges can be diffed against any of their parents
+    if parent == 0 || (parent > 1 && parent > raw_commit.parent_count()) {
+        return Ok(None);
+    }
+
+    let refs = ref_index(&repo)?;
+    let mut commit = Commit::new(&raw_commit)?;
+    commit.refs = refs.get(&raw_commit.id()).cloned().unwrap_or_default();
+    // Refs pointing at the commit itself are already shown as its badges.
+    // Every check can walk the whole history, so only a limited number of refs
+    // are checked, starting with the default branch and then other branches.
+    let default = match repo.head() {
+        Ok(ref head) if head.is_branch() => head.shorthand().map(|name| name.to_string()),
+        _ => None,
+    };
+    let mut candidates: Vec<_> = refs.iter()
+        .filter(|&(oid, _)| *oid != raw_commit.id())
+        .flat_map(|(oid, badges)| badges.iter().map(move |badge| (oid, badge)))
+        .collect();
+    candidates.sort_by_key(|&(_, badge)| {
+        (badge.tag || default.as_ref() != Some(&badge.name), badge.tag, badge.name.clone())
+    });
+    let more_refs = candidates.len() > MAX_CONTAINING_REFS;
+    let mut contained_in = Vec::new();
+    for (oid, badge) in candidates.into_iter().take(MAX_CONTAINING_REFS) {
+        if repo.graph_descendant_of(*oid, raw_commit.id())? {
+            contained_in.push(badge.clone());
+        }
+    }
 
-    let commit = Commit::new(&raw_commit)?;
     let tree = raw_commit.tree()?;
     let (items, readme) = read_tree(&repo, &tree)?;
 
+    let parents = raw_commit.parents().enumerate().map(|(i, p)| {
+        let id = p.id().to_string();
+        CommitParent {
+            number: i + 1,
+            short_id: id[..7].to_string(),
+            id: id,
+            selected: i + 1 == parent,
+        }
+    }).collect();
+    let parent_tree = match raw_commit.parent(parent - 1) {
+        Ok(p) => Some(p.tree()?),
+        // The first commit is diffed against an empty tree
+        Err(_) => None,
+    };
+    let files = diff_trees(&repo, parent_tree.as_ref(), &tree)?;
+
     let tmpl = CommitTmpl {
         mount: &ctx.mount,
         username: username,
@@ -305,6 +747,12 @@ pub fn commit<'a, 'b>(ctx: &'a Context, username: &'b str, repo_info: Repo, id:
         commit: commit,
         items: items,
         readme: readme,
+        contained_in: contained_in,
+        more_refs: more_refs,
+        additions: files.iter().map(|f| f.additions).sum(),
+        deletions: files.iter().map(|f| f.deletions).sum(),
+        parents: parents,
+        files: files,
     };
     Ok(Some(tmpl))
 }
@@ -1,14 +1,13 @@
 use {Context, Result};
+use super::util::build_repo_path;
 
 use std::process;
 use std::io::Write;
 
-pub fn info(ctx: &Context, username: &str, repo_name: &str) -> Result<Vec<u8>> {
-    let mut root_dir = ctx.repo_dir.clone();
-    root_dir.push(username);
-    root_dir.push(repo_name);
+pub fn info(ctx: &Context, username: &str, repo_name: &str, service: &str) -> Result<Vec<u8>> {
+    let root_dir = build_repo_path(ctx, username, repo_name);
 
-    let command = process::Command::new("git-upload-pack")
+    let command = process::Command::new(service)
         .arg("--stateless-rpc")
         .arg("--advertise-refs")
         .arg(root_dir)
@@ -20,9 +19,7 @@ pub fn info(ctx: &Context, username: &str, repo_name: &str) -> Result<Vec<u8>> {
 }
 
 pub fn pull(ctx: &Context, username: &str, repo_name: &str, body: &[u8]) -> Result<Vec<u8>> {
-    let mut root_dir = ctx.repo_dir.clone();
-    root_dir.push(username);
-    root_dir.push(repo_name);
+    let root_dir = build_repo_path(ctx, username, repo_name);
 
     let mut command = process::Command::new("git-upload-pack")
         .arg("--stateless-rpc")
@@ -43,3 +40,27 @@ pub fn pull(ctx: &Context, username: &str, repo_name: &str, body: &[u8]) -> Resu
 
     Ok(output.stdout)
 }
+
+/// Runs `git-receive-pack` for a push made over HTTP by the user `user_id`.
+/// The user is passed to the pre-receive hook through the environment.
+pub fn push(ctx: &Context, username: &str, repo_name: &str, user_id: i32, body: &[u8])
+    -> Result<Vec<u8>>
+{
+    let root_dir = build_repo_path(ctx, username, repo_name);
+
+    let mut command = process::Command::new("git-receive-pack")
+        .arg("--stateless-rpc")
+        .arg(root_dir)
+        .env("VALENTINE_KEY_ID", format!("user-{}", user_id))
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()?;
+
+    {
+        let stdin = command.stdin.as_mut().unwrap();
+        stdin.write_all(body)?;
+    }
+    let output = command.wait_with_output()?;
+
+    Ok(output.stdout)
+}
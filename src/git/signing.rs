@@ -0,0 +1,129 @@
+use Result;
+use types::SigningKey;
+
+use rand::{self, Rng};
+use rand::distributions::Alphanumeric;
+
+use std::{env, fs, process};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Verifies commit signatures against the signing keys registered with
+/// Valentine. SSH signatures are checked with `ssh-keygen` and GPG signatures
+/// with `gpg`, using a scratch directory that is removed on drop.
+pub struct Verifier {
+    dir: PathBuf,
+}
+
+impl Verifier {
+    pub fn new(keys: &[SigningKey]) -> Result<Self> {
+        let name: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let dir = env::temp_dir().join(format!("valentine-verify-{}", name));
+        fs::create_dir(&dir)?;
+        let verifier = Verifier { dir: dir };
+
+        let gnupg = verifier.dir.join("gnupg");
+        fs::create_dir(&gnupg)?;
+        fs::set_permissions(&gnupg, fs::Permissions::from_mode(0o700))?;
+
+        let mut signers = String::new();
+        for key in keys {
+            if key.kind == "gpg" {
+                let mut cmd = process::Command::new("gpg");
+                cmd.arg("--homedir").arg(&gnupg).arg("--batch").arg("--import");
+                run(&mut cmd, key.content.as_bytes())?;
+            } else {
+                signers.push_str(&format!("user-{} namespaces=\"git\" {}\n", key.owner, key.content));
+            }
+        }
+        fs::write(verifier.dir.join("allowed_signers"), signers)?;
+
+        Ok(verifier)
+    }
+
+    /// Returns true if `signature` is a valid signature of `data` made by a
+    /// registered key.
+    pub fn verify(&self, signature: &[u8], data: &[u8]) -> Result<bool> {
+        let sig_path = self.dir.join("signature");
+        fs::write(&sig_path, signature)?;
+
+        if signature.starts_with(b"-----BEGIN SSH SIGNATURE-----") {
+            self.verify_ssh(&sig_path, data)
+        } else if signature.starts_with(b"-----BEGIN PGP SIGNATURE-----") {
+            self.verify_gpg(&sig_path, data)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn verify_ssh(&self, sig_path: &Path, data: &[u8]) -> Result<bool> {
+        let signers = self.dir.join("allowed_signers");
+        let mut cmd = process::Command::new("ssh-keygen");
+        cmd.args(&["-Y", "find-principals", "-f"]).arg(&signers).arg("-s").arg(sig_path);
+        let output = run(&mut cmd, &[])?;
+        if !output.status.success() {
+            return Ok(false);
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let principal = match stdout.lines().next() {
+            Some(p) => p.to_string(),
+            None => return Ok(false),
+        };
+
+        let mut cmd = process::Command::new("ssh-keygen");
+        cmd.args(&["-Y", "verify", "-n", "git", "-f"]).arg(&signers)
+            .arg("-I").arg(principal)
+            .arg("-s").arg(sig_path);
+        Ok(run(&mut cmd, data)?.status.success())
+    }
+
+    fn verify_gpg(&self, sig_path: &Path, data: &[u8]) -> Result<bool> {
+        let mut cmd = process::Command::new("gpg");
+        cmd.arg("--homedir").arg(self.dir.join("gnupg"))
+            .args(&["--batch", "--status-fd", "1", "--verify"])
+            .arg(sig_path)
+            .arg("-");
+        let output = run(&mut cmd, data)?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(output.status.success() && stdout.lines().any(|l| l.starts_with("[GNUPG:] VALIDSIG ")))
+    }
+}
+
+impl Drop for Verifier {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Reads the fingerprint of an armored GPG public key.
+pub fn gpg_fingerprint(key: &str) -> Result<Option<String>> {
+    let mut cmd = process::Command::new("gpg");
+    cmd.args(&["--batch", "--with-colons", "--import-options", "show-only", "--import"]);
+    let output = run(&mut cmd, key.as_bytes())?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.lines()
+       .find(|l| l.starts_with("fpr:"))
+       .and_then(|l| l.split(':').nth(9))
+       .map(|f| f.to_string()))
+}
+
+fn run(cmd: &mut process::Command, stdin: &[u8]) -> Result<process::Output> {
+    let mut child = cmd.stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()?;
+    {
+        let child_stdin = child.stdin.as_mut().unwrap();
+        child_stdin.write_all(stdin)?;
+    }
+    Ok(child.wait_with_output()?)
+}
@@ -1,10 +1,14 @@
-use {Context, Result};
+use {highlight, Context, Result};
 use types::*;
 
+use super::LOG_PAGE_SIZE;
+
 use git2::{self, ObjectType, Repository};
 use pulldown_cmark;
 
-use std::path::PathBuf;
+use std::cmp;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 pub fn build_repo_path(ctx: &Context, username: &str, reponame: &str) -> PathBuf {
     let mut reponame = reponame.to_string();
@@ -15,6 +19,11 @@ pub fn build_repo_path(ctx: &Context, username: &str, reponame: &str) -> PathBuf
     ctx.repo_dir.join(username).join(reponame)
 }
 
+/// Quotes `path` for use as a single word in a shell script.
+pub fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', "'\\''"))
+}
+
 pub fn read_readme<'repo>(repo: &Repository, tree: &git2::Tree<'repo>) -> Result<Option<String>> {
     for entry in tree.iter() {
         let name = entry.name().unwrap_or("Invalid filename").to_string();
@@ -97,6 +106,20 @@ pub fn read_file<'iter>(repo: &Repository, entry: &git2::TreeEntry<'iter>)
     Ok(String::from_utf8(blob.content().to_vec()).ok())
 }
 
+/// Reads the `linguist-language` of `path` from the `.gitattributes` at the
+/// root of `tree`.
+pub fn linguist_language<'repo>(repo: &Repository, tree: &git2::Tree<'repo>, path: &str)
+    -> Result<Option<String>>
+{
+    let entry = match tree.get_name(".gitattributes") {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+    Ok(read_file(repo, &entry)?.and_then(|attributes| {
+        highlight::linguist_language(&attributes, path)
+    }))
+}
+
 pub fn get_ref<'a>(repo: &'a Repository, name: &str) -> Result<Option<git2::Reference<'a>>> {
     // HEAD must be handled specially
     if name == "HEAD" {
@@ -127,3 +150,249 @@ pub fn get_commit<'a>(repo: &'a Repository, id: &str) -> Result<Option<git2::Com
         Ok(Some(reference.peel_to_commit()?))
     }
 }
+
+/// Lists the names of all branches and tags.
+pub fn list_refs(repo: &Repository) -> Result<(Vec<Branch>, Vec<Tag>)> {
+    let mut branches = Vec::new();
+    for branch in repo.branches(None)? {
+        if let Some(name) = branch?.0.name()? {
+            branches.push(Branch { name: name.to_string() });
+        }
+    }
+
+    let mut tags = Vec::new();
+    for tag in repo.tag_names(None)?.iter() {
+        if let Some(name) = tag {
+            tags.push(Tag { name: name.to_string() });
+        }
+    }
+    Ok((branches, tags))
+}
+
+/// The id of the tree or blob at `path`, if there is one.
+pub fn path_id<'repo>(tree: &git2::Tree<'repo>, path: &str) -> Option<git2::Oid> {
+    tree.get_path(Path::new(path)).ok().map(|entry| entry.id())
+}
+
+/// Finds the file that was renamed to `path` between `old` and `new`.
+pub fn find_rename<'repo>(repo: &Repository, old: &git2::Tree<'repo>, new: &git2::Tree<'repo>,
+                          path: &str)
+    -> Result<Option<String>>
+{
+    let mut diff = repo.diff_tree_to_tree(Some(old), Some(new), None)?;
+    diff.find_similar(Some(git2::DiffFindOptions::new().renames(true)))?;
+    let path = Path::new(path);
+    for delta in diff.deltas() {
+        if delta.status() == git2::Delta::Renamed && delta.new_file().path() == Some(path) {
+            return Ok(delta.old_file().path().map(|p| p.to_string_lossy().into_owned()));
+        }
+    }
+    Ok(None)
+}
+
+/// Maps commits to the branches and tags pointing at them, branches first.
+pub fn ref_index(repo: &Repository) -> Result<HashMap<git2::Oid, Vec<RefBadge>>> {
+    let mut index: HashMap<_, Vec<_>> = HashMap::new();
+    for reference in repo.references()? {
+        let reference = reference?;
+        let tag = reference.is_tag();
+        if !tag && !reference.is_branch() {
+            continue;
+        }
+        let name = match reference.shorthand() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        // Annotated tags point at a tag object rather than the commit, and
+        // tags can point at trees and blobs which are skipped
+        let commit = match reference.peel_to_commit() {
+            Ok(commit) => commit,
+            Err(_) => continue,
+        };
+        index.entry(commit.id()).or_insert_with(Vec::new).push(RefBadge {
+            name: name,
+            tag: tag,
+        });
+    }
+    for badges in index.values_mut() {
+        badges.sort_by(|a, b| (a.tag, &a.name).cmp(&(b.tag, &b.name)));
+    }
+    Ok(index)
+}
+
+/// Reads a page of commits from `oids`, a walk of the log. Pages either start
+/// after the commit `after`, or are numbered from 1 when `after` isn't given.
+/// Returns the commits and the cursor of the next page, or None if the cursor
+/// isn't one of the commits.
+pub fn log_page<I>(repo: &Repository, oids: I, page: usize, after: Option<&str>)
+    -> Result<Option<(Vec<Commit>, Option<String>)>>
+    where I: Iterator<Item = Result<git2::Oid>>
+{
+    let mut after = match after.map(git2::Oid::from_str) {
+        Some(Ok(oid)) => Some(oid),
+        Some(Err(_)) => return Ok(None),
+        None => None,
+    };
+    let mut skip = if after.is_none() { (cmp::max(page, 1) - 1) * LOG_PAGE_SIZE } else { 0 };
+
+    let refs = ref_index(repo)?;
+    let mut log = Vec::new();
+    let mut next_page = None;
+    for oid in oids {
+        let oid = oid?;
+        if let Some(cursor) = after {
+            if oid == cursor {
+                after = None;
+            }
+            continue;
+        }
+        if skip > 0 {
+            skip -= 1;
+            continue;
+        }
+        // Stop as soon as it's known there's another page
+        if log.len() == LOG_PAGE_SIZE {
+            next_page = log.last().map(|c: &Commit| c.id.clone());
+            break;
+        }
+
+        let commit = repo.find_commit(oid)?;
+        let mut item = Commit::new(&commit)?;
+        item.refs = refs.get(&oid).cloned().unwrap_or_default();
+        log.push(item);
+    }
+
+    if after.is_some() {
+        return Ok(None);
+    }
+    Ok(Some((log, next_page)))
+}
+
+/// Diffs with more lines than this start out collapsed.
+const COLLAPSE_DIFF_LINES: usize = 400;
+/// Diffs with more lines than this aren't shown at all.
+const MAX_DIFF_LINES: usize = 5000;
+/// Once this many lines were shown, the diffs of the remaining files are left
+/// out too.
+const MAX_TOTAL_DIFF_LINES: usize = 20000;
+
+/// Diffs two trees file by file, detecting renames. An empty `old` tree
+/// shows every file as added.
+pub fn diff_trees<'repo>(repo: &Repository, old: Option<&git2::Tree<'repo>>,
+                         new: &git2::Tree<'repo>)
+    -> Result<Vec<DiffFile>>
+{
+    let mut diff = repo.diff_tree_to_tree(old, Some(new), None)?;
+    diff.find_similar(Some(git2::DiffFindOptions::new().renames(true)))?;
+
+    let mut files = Vec::new();
+    let mut total_lines = 0;
+    let mut budget_left = true;
+    for (i, delta) in diff.deltas().enumerate() {
+        let path = |file: git2::DiffFile| {
+            file.path().map_or(String::new(), |p| p.to_string_lossy().into_owned())
+        };
+        let status = match delta.status() {
+            git2::Delta::Added => "added",
+            git2::Delta::Deleted => "deleted",
+            git2::Delta::Renamed => "renamed",
+            git2::Delta::Copied => "copied",
+            git2::Delta::Typechange => "typechange",
+            _ => "modified",
+        };
+        let mut file = DiffFile {
+            old_path: path(delta.old_file()),
+            new_path: path(delta.new_file()),
+            status: status,
+            additions: 0,
+            deletions: 0,
+            binary: false,
+            counted: false,
+            collapsed: false,
+            too_large: false,
+            lines: Vec::new(),
+        };
+        // Added and deleted files only have a path on one side
+        if file.old_path.is_empty() {
+            file.old_path = file.new_path.clone();
+        } else if file.new_path.is_empty() {
+            file.new_path = file.old_path.clone();
+        }
+        // Generating the patch is the slow part, so it's skipped entirely
+        if !budget_left {
+            file.collapsed = true;
+            file.too_large = true;
+            files.push(file);
+            continue;
+        }
+
+        let patch = match git2::Patch::from_diff(&diff, i)? {
+            Some(patch) => patch,
+            None => {
+                file.binary = true;
+                file.collapsed = true;
+                files.push(file);
+                continue;
+            }
+        };
+        // Binary detection happens while the patch is generated
+        if patch.delta().flags().is_binary() {
+            file.binary = true;
+            file.collapsed = true;
+            files.push(file);
+            continue;
+        }
+
+        let (_, additions, deletions) = patch.line_stats()?;
+        file.additions = additions;
+        file.deletions = deletions;
+        file.counted = true;
+        let mut line_count = 0;
+        for h in 0..patch.num_hunks() {
+            line_count += patch.num_lines_in_hunk(h)?;
+        }
+        file.collapsed = line_count > COLLAPSE_DIFF_LINES;
+        if line_count > MAX_DIFF_LINES {
+            file.too_large = true;
+        } else if total_lines + line_count > MAX_TOTAL_DIFF_LINES {
+            file.too_large = true;
+            budget_left = false;
+        } else {
+            total_lines += line_count;
+            file.lines = diff_lines(&patch)?;
+        }
+        files.push(file);
+    }
+    Ok(files)
+}
+
+fn diff_lines(patch: &git2::Patch) -> Result<Vec<DiffLine>> {
+    let mut lines = Vec::new();
+    for h in 0..patch.num_hunks() {
+        let (hunk, line_count) = patch.hunk(h)?;
+        lines.push(DiffLine {
+            kind: "hunk",
+            old_line: None,
+            new_line: None,
+            content: String::from_utf8_lossy(hunk.header()).trim_end().to_string(),
+        });
+        for l in 0..line_count {
+            let line = patch.line_in_hunk(h, l)?;
+            let kind = match line.origin() {
+                '+' => "add",
+                '-' => "del",
+                ' ' => "ctx",
+                // "No newline at end of file" markers
+                _ => continue,
+            };
+            let content = String::from_utf8_lossy(line.content());
+            lines.push(DiffLine {
+                kind: kind,
+                old_line: line.old_lineno(),
+                new_line: line.new_lineno(),
+                content: content.trim_end_matches(|c| c == '\n' || c == '\r').to_string(),
+            });
+        }
+    }
+    Ok(lines)
+}
@@ -0,0 +1,264 @@
+//! Server-side syntax highlighting of source files. Lines are rendered to
+//! HTML with css classes, so the light and dark themes are both just
+//! stylesheets. Highlighted files are cached by blob id.
+
+use syntect::html::{css_for_theme_with_class_style, line_tokens_to_classed_spans, ClassStyle};
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxReference, SyntaxSet};
+use syntect::highlighting::ThemeSet;
+use syntect::util::LinesWithEndings;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+/// Bigger files are shown without highlighting.
+const MAX_BYTES: usize = 512 * 1024;
+/// The cache is emptied when the highlighted files in it grow past this size.
+const MAX_CACHED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Highlighted lines keyed by blob id and syntax name, along with their total
+/// size.
+#[derive(Default)]
+struct Cache {
+    files: HashMap<(String, String), Arc<Vec<String>>>,
+    bytes: usize,
+}
+
+pub struct Highlighter {
+    syntaxes: SyntaxSet,
+    cache: Mutex<Cache>,
+    /// The light theme, followed by the dark theme for browsers that prefer it.
+    pub css: String,
+}
+
+impl Highlighter {
+    pub fn new() -> Self {
+        let themes = ThemeSet::load_defaults();
+        let light = css_for_theme_with_class_style(&themes.themes["InspiredGitHub"], CLASS_STYLE)
+            .unwrap_or_default();
+        let dark = css_for_theme_with_class_style(&themes.themes["base16-ocean.dark"], CLASS_STYLE)
+            .unwrap_or_default();
+
+        Highlighter {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            cache: Mutex::new(Cache::default()),
+            css: format!("{}\n@media (prefers-color-scheme: dark) {{\n{}\n}}\n", light, dark),
+        }
+    }
+
+    /// Picks the syntax of a file, from its `linguist-language` attribute,
+    /// then its name and finally its shebang.
+    fn find_syntax(&self, path: &str, content: &str, language: Option<&str>)
+        -> Option<&SyntaxReference>
+    {
+        if let Some(language) = language {
+            // Names with spaces are written with dashes in attributes
+            let by_name = self.syntaxes.syntaxes().iter().find(|s| {
+                s.name.eq_ignore_ascii_case(language) ||
+                    s.name.replace(' ', "-").eq_ignore_ascii_case(language)
+            });
+            if let Some(syntax) = by_name.or_else(|| self.syntaxes.find_syntax_by_token(language)) {
+                return Some(syntax);
+            }
+        }
+
+        // Files like Makefile are matched on their whole name
+        let name = path.rsplit('/').next().unwrap_or(path);
+        let extension = name.rsplit('.').next().unwrap_or(name);
+        self.syntaxes.find_syntax_by_extension(name)
+            .or_else(|| self.syntaxes.find_syntax_by_extension(extension))
+            .or_else(|| self.syntaxes.find_syntax_by_first_line(content.lines().next().unwrap_or("")))
+    }
+
+    /// Highlights the blob `id` at `path`, returning one HTML string per
+    /// line. Returns `None` if the syntax isn't known or the file is too big.
+    pub fn highlight(&self, id: &str, path: &str, content: &str, language: Option<&str>)
+        -> Option<Arc<Vec<String>>>
+    {
+        if content.len() > MAX_BYTES {
+            return None;
+        }
+        let syntax = try_opt!(self.find_syntax(path, content, language));
+        let key = (id.to_string(), syntax.name.clone());
+        if let Some(lines) = self.cache.lock().unwrap().files.get(&key) {
+            return Some(lines.clone());
+        }
+
+        let lines = Arc::new(try_opt!(self.render(syntax, content)));
+        let bytes = lines.iter().map(|line| line.len()).sum::<usize>();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.bytes + bytes > MAX_CACHED_BYTES {
+            cache.files.clear();
+            cache.bytes = 0;
+        }
+        if cache.files.insert(key, lines.clone()).is_none() {
+            cache.bytes += bytes;
+        }
+        Some(lines)
+    }
+
+    fn render(&self, syntax: &SyntaxReference, content: &str) -> Option<Vec<String>> {
+        let mut state = ParseState::new(syntax);
+        let mut stack = ScopeStack::new();
+        let mut lines = Vec::new();
+        for line in LinesWithEndings::from(content) {
+            // Every line is its own table row, so the spans still open from
+            // the previous line are opened again and closed at the end
+            let mut html = stack.as_slice().iter()
+                .map(|scope| format!("<span class=\"{}\">", scope_classes(scope)))
+                .collect::<String>();
+            let ops = try_opt!(state.parse_line(line, &self.syntaxes).ok());
+            let (spans, _) = try_opt!(line_tokens_to_classed_spans(line, &ops, CLASS_STYLE,
+                                                                   &mut stack).ok());
+            html.push_str(&spans.replace(|c: char| c == '\n' || c == '\r', ""));
+            for _ in 0..stack.len() {
+                html.push_str("</span>");
+            }
+            lines.push(html);
+        }
+        Some(lines)
+    }
+}
+
+/// The classes syntect gives a scope, `keyword.control` becomes
+/// `hl-keyword hl-control`.
+fn scope_classes(scope: &Scope) -> String {
+    scope.build_string().split('.')
+        .map(|atom| format!("hl-{}", atom))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Finds the `linguist-language` set for `path` in a `.gitattributes` file.
+/// Later lines take precedence, like in git.
+pub fn linguist_language(attributes: &str, path: &str) -> Option<String> {
+    let mut language = None;
+    for line in attributes.lines() {
+        let mut parts = line.split_whitespace();
+        let pattern = match parts.next() {
+            Some(p) if !p.starts_with('#') => p,
+            _ => continue,
+        };
+        let value = parts.filter_map(|attr| {
+            let mut attr = attr.splitn(2, '=');
+            match (attr.next(), attr.next()) {
+                (Some("linguist-language"), Some(value)) => Some(value),
+                _ => None,
+            }
+        }).last();
+        if let Some(value) = value {
+            if pattern_matches(pattern, path) {
+                language = Some(value.to_string());
+            }
+        }
+    }
+    language
+}
+
+/// Patterns without a slash match the file name anywhere in the tree, others
+/// match the whole path from the root.
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    if pattern.contains('/') {
+        glob_matches(pattern.trim_start_matches('/').as_bytes(), path.as_bytes())
+    } else {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        glob_matches(pattern.as_bytes(), name.as_bytes())
+    }
+}
+
+/// Matches `*` and `?` wildcards, `*` doesn't match slashes but `**` does.
+fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((&b'*', rest)) => {
+            let (any, rest) = match rest.split_first() {
+                Some((&b'*', rest)) => (true, rest),
+                _ => (false, rest),
+            };
+            for i in 0..text.len() + 1 {
+                if glob_matches(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' && !any {
+                    return false;
+                }
+            }
+            false
+        }
+        Some((&b'?', rest)) => match text.split_first() {
+            Some((&c, text)) if c != b'/' => glob_matches(rest, text),
+            _ => false,
+        },
+        Some((&c, rest)) => match text.split_first() {
+            Some((&t, text)) if t == c => glob_matches(rest, text),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, text: &str) -> bool {
+        glob_matches(pattern.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn glob_literal() {
+        assert!(glob("Makefile", "Makefile"));
+        assert!(!glob("Makefile", "makefile"));
+        assert!(!glob("Makefile", "Makefile.in"));
+        assert!(glob("", ""));
+        assert!(!glob("", "a"));
+    }
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob("*.rs", "main.rs"));
+        assert!(glob("*.rs", ".rs"));
+        assert!(!glob("*.rs", "main.rs.bak"));
+        assert!(glob("main.?s", "main.rs"));
+        assert!(!glob("main.?s", "main.s"));
+        assert!(glob("*", ""));
+        assert!(glob("a*b*c", "aXXbYYc"));
+    }
+
+    #[test]
+    fn glob_slashes() {
+        assert!(!glob("src/*.rs", "src/git/mod.rs"));
+        assert!(glob("src/*/mod.rs", "src/git/mod.rs"));
+        assert!(glob("src/**.rs", "src/git/mod.rs"));
+        assert!(glob("src/**/mod.rs", "src/git/mod.rs"));
+        assert!(!glob("src?git", "src/git"));
+    }
+
+    #[test]
+    fn language_by_name_and_path() {
+        let attributes = "*.h linguist-language=C++\n\
+                          vendor/*.h linguist-language=C\n";
+        assert_eq!(linguist_language(attributes, "include/foo.h"), Some(String::from("C++")));
+        assert_eq!(linguist_language(attributes, "vendor/foo.h"), Some(String::from("C")));
+        assert_eq!(linguist_language(attributes, "lib/vendor/foo.h"), Some(String::from("C++")));
+        assert_eq!(linguist_language(attributes, "foo.c"), None);
+    }
+
+    #[test]
+    fn language_last_line_wins() {
+        let attributes = "*.inc linguist-language=PHP\n\
+                          *.inc linguist-language=Pascal\n";
+        assert_eq!(linguist_language(attributes, "a.inc"), Some(String::from("Pascal")));
+
+        let attributes = "*.inc linguist-language=PHP linguist-language=Pascal\n";
+        assert_eq!(linguist_language(attributes, "a.inc"), Some(String::from("Pascal")));
+    }
+
+    #[test]
+    fn language_ignores_other_lines() {
+        let attributes = "# *.txt linguist-language=Markdown\n\
+                          \n\
+                          *.txt text eol=lf\n\
+                          *.txt linguist-vendored\n";
+        assert_eq!(linguist_language(attributes, "notes.txt"), None);
+    }
+}
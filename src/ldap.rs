@@ -0,0 +1,235 @@
+//! Authenticating users against an LDAP directory. Users are found with a
+//! search and their password is checked by binding as them. Directory users
+//! get an account on first login, and their admin status and organization
+//! memberships follow their groups.
+
+use {db, git, Context, LdapDirectory, Result};
+use db::Pool;
+use types::NewUser;
+
+use ldap3::{ldap_escape, LdapConn, ResultEntry, Scope, SearchEntry};
+
+/// The result code of searching below an entry that doesn't exist.
+const NO_SUCH_OBJECT: u32 = 32;
+
+/// A user's entry in the directory.
+pub struct DirectoryUser {
+    pub dn: String,
+    /// The username as stored in the directory, which can differ in case
+    /// from the one the user typed
+    pub username: Option<String>,
+    pub email: String,
+    pub groups: Vec<String>,
+}
+
+impl DirectoryUser {
+    fn in_group(&self, group: &str) -> bool {
+        // DNs are case insensitive
+        self.groups.iter().any(|g| g.eq_ignore_ascii_case(group))
+    }
+}
+
+/// A connection bound as the search account.
+pub struct Directory<'a> {
+    config: &'a LdapDirectory,
+    conn: LdapConn,
+}
+
+impl<'a> Directory<'a> {
+    pub fn connect(config: &'a LdapDirectory) -> Result<Self> {
+        let mut conn = LdapConn::new(&config.url).map_err(Box::new)?;
+        if let Some(ref dn) = config.bind_dn {
+            let password = config.bind_password.as_ref().map_or("", |p| p.as_str());
+            conn.simple_bind(dn, password).and_then(|r| r.success()).map_err(Box::new)?;
+        }
+        Ok(Directory {
+            config: config,
+            conn: conn,
+        })
+    }
+
+    fn attributes(&self) -> (&'a str, &'a str, &'a str) {
+        let config = self.config;
+        (config.username_attribute.as_ref().map_or("uid", |a| a.as_str()),
+         config.email_attribute.as_ref().map_or("mail", |a| a.as_str()),
+         config.group_attribute.as_ref().map_or("memberOf", |a| a.as_str()))
+    }
+
+    fn user(&self, entry: ResultEntry) -> DirectoryUser {
+        let (username_attr, email_attr, group_attr) = self.attributes();
+        let mut entry = SearchEntry::construct(entry);
+        let email = entry.attrs.remove(email_attr).and_then(|mut v| v.pop()).unwrap_or_default();
+        DirectoryUser {
+            dn: entry.dn,
+            username: entry.attrs.remove(username_attr).and_then(|mut v| v.pop()),
+            email: email,
+            groups: entry.attrs.remove(group_attr).unwrap_or_default(),
+        }
+    }
+
+    /// Looks up `username`, returning `None` unless exactly one entry matches.
+    pub fn find(&mut self, username: &str) -> Result<Option<DirectoryUser>> {
+        let (username_attr, email_attr, group_attr) = self.attributes();
+        let filter = self.config.user_filter.replace("{username}", &ldap_escape(username));
+
+        let (mut entries, _) = self.conn.search(&self.config.base_dn, Scope::Subtree, &filter,
+                                                vec![username_attr, email_attr, group_attr])
+            .and_then(|r| r.success())
+            .map_err(Box::new)?;
+        if entries.len() != 1 {
+            return Ok(None);
+        }
+        Ok(Some(self.user(entries.remove(0))))
+    }
+
+    /// Reads the entry at `dn`, returning `None` only if the directory says
+    /// there is no such entry.
+    pub fn find_dn(&mut self, dn: &str) -> Result<Option<DirectoryUser>> {
+        let (username_attr, email_attr, group_attr) = self.attributes();
+        let result = self.conn.search(dn, Scope::Base, "(objectClass=*)",
+                                      vec![username_attr, email_attr, group_attr])
+            .map_err(Box::new)?;
+        if result.1.rc == NO_SUCH_OBJECT {
+            return Ok(None);
+        }
+        let (mut entries, _) = result.success().map_err(Box::new)?;
+        match entries.pop() {
+            Some(entry) => Ok(Some(self.user(entry))),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks `password` by binding as `dn`. The connection stays bound as
+    /// that user, so nothing can be searched afterwards.
+    fn bind(mut self, dn: &str, password: &str) -> Result<bool> {
+        Ok(self.conn.simple_bind(dn, password).map_err(Box::new)?.success().is_ok())
+    }
+}
+
+impl<'a> Drop for Directory<'a> {
+    fn drop(&mut self) {
+        let _ = self.conn.unbind();
+    }
+}
+
+/// Checks the password of `username` by binding as them. Returns `None` if
+/// the user isn't in the directory or the password is wrong.
+pub fn authenticate(config: &LdapDirectory, username: &str, password: &str)
+    -> Result<Option<DirectoryUser>>
+{
+    // An empty password would make this an anonymous bind, which succeeds
+    if password.is_empty() {
+        return Ok(None);
+    }
+
+    let mut directory = Directory::connect(config)?;
+    let user = match directory.find(username)? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    if directory.bind(&user.dn, password)? {
+        Ok(Some(user))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Creates or updates the account of a directory user, returning its id.
+/// Accounts are matched on their DN, and new ones are named after the
+/// directory's username rather than what the user typed. Returns `None` if
+/// that name can't be used or is taken by an account that isn't linked to the
+/// directory.
+pub fn sync_user(ctx: &Context, config: &LdapDirectory, user: &DirectoryUser) -> Result<Option<i32>> {
+    let pool = &ctx.db_pool;
+    if let Some(id) = db::read::user_by_ldap_dn(pool, &user.dn)? {
+        update_user(pool, config, id, user)?;
+        return Ok(Some(id));
+    }
+
+    let username = match user.username {
+        Some(ref username) => username,
+        None => {
+            warn!("{} has no username in the directory", user.dn);
+            return Ok(None);
+        }
+    };
+    // Otherwise anyone in the directory could take over the local account
+    // with their name, including its admin status
+    if db::read::user_exists(pool, username)? {
+        warn!("{} is in the directory but already has an account, an admin has to link it", username);
+        return Ok(None);
+    }
+    let new_user = match NewUser::external(username.clone(), user.email.clone()) {
+        Some(new_user) => new_user,
+        None => return Ok(None),
+    };
+    db::create::user(pool, &new_user)?;
+    git::create_user(ctx, username)?;
+    info!("created user {} from the directory", username);
+
+    let id = db::read::user_id(pool, username)?;
+    update_user(pool, config, id, user)?;
+    Ok(Some(id))
+}
+
+/// Links the existing account `id` to the directory entry of `username`, from
+/// then on it logs in with the directory password. Returns `false` if the
+/// directory has no such user or the entry is linked to another account.
+pub fn link_user(ctx: &Context, config: &LdapDirectory, id: i32, username: &str) -> Result<bool> {
+    let pool = &ctx.db_pool;
+    let user = match Directory::connect(config)?.find(username)? {
+        Some(user) => user,
+        None => return Ok(false),
+    };
+    match db::read::user_by_ldap_dn(pool, &user.dn)? {
+        Some(other) if other != id => return Ok(false),
+        _ => (),
+    }
+    update_user(pool, config, id, &user)?;
+    Ok(true)
+}
+
+/// Updates the admin status and organization memberships of a directory user
+/// from their groups.
+fn update_user(pool: &Pool, config: &LdapDirectory, id: i32, user: &DirectoryUser) -> Result<()> {
+    db::update::ldap_dn(pool, id, &user.dn)?;
+    if let Some(ref group) = config.admin_group {
+        db::update::user_admin(pool, id, user.in_group(group))?;
+    }
+
+    if let Some(ref orgs) = config.org_groups {
+        for (group, org) in orgs {
+            if !db::read::is_org(pool, org)? {
+                warn!("the directory group {} maps to {}, which is not an organization", group, org);
+                continue;
+            }
+            // Owners are managed in Valentine, only plain members follow the group
+            let org_id = db::read::user_id(pool, org)?;
+            match (user.in_group(group), db::read::org_role(pool, org_id, id)?) {
+                (true, None) => db::create::org_member(pool, org_id, id, false)?,
+                (false, Some(false)) => db::delete::org_member(pool, org_id, id)?,
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Brings all directory users up to date, disabling the ones that were
+/// removed from the directory.
+pub fn sync(pool: &Pool, config: &LdapDirectory) -> Result<()> {
+    let mut directory = Directory::connect(config)?;
+    for (id, username, dn) in db::read::ldap_users(pool)? {
+        // Users are followed by their entry, so that they aren't disabled if
+        // their username changes or is shared by another entry
+        match directory.find_dn(&dn)? {
+            Some(user) => update_user(pool, config, id, &user)?,
+            None => {
+                info!("disabling {}, they were removed from the directory", username);
+                db::update::user_disabled(pool, id, true)?;
+                db::delete::sessions(pool, id, None)?;
+            }
+        }
+    }
+    Ok(())
+}
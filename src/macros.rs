@@ -22,11 +22,13 @@ macro_rules! redirect {
     };
 }
 
+/// Returns the `CurrentUser` of the request, redirecting to the login page if
+/// nobody is logged in.
 macro_rules! check_login {
-    ( $cookies:expr, $res:expr, $ctx:expr ) => {
+    ( $req:ident, $cookies:expr, $res:expr, $ctx:expr ) => {
         {
-            if let Some(name) = util::check_login($ctx, $cookies) {
-                name
+            if let Some(user) = util::current_user($req, $ctx, $cookies) {
+                user
             } else {
                 return Ok($res.redirect(Status::FORBIDDEN, "/login", "You must be logged in for this"));
             }
@@ -34,6 +36,18 @@ macro_rules! check_login {
     };
 }
 
+/// Rejects form submissions that don't carry the CSRF token of the session.
+macro_rules! check_csrf {
+    ( $req:ident, $cookies:expr, $res:ident, $ctx:ident ) => {
+        {
+            let token = $req.form_value("csrf_token").unwrap_or_default();
+            if !util::valid_csrf($cookies, &token) {
+                redirect!($res, $ctx, "", "Your session has expired, please try again");
+            }
+        }
+    };
+}
+
 macro_rules! parse_param {
     ( $req:ident, $res:ident, $ctx:ident, $name:expr, $t:ty) => {
         {
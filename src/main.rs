@@ -9,8 +9,10 @@ extern crate env_logger;
 #[macro_use] extern crate explode;
 extern crate git2;
 #[macro_use] extern crate hayaku;
+extern crate hmac;
 extern crate html_escape;
 extern crate humansize;
+extern crate ldap3;
 #[macro_use] extern crate log;
 extern crate pulldown_cmark;
 #[macro_use] extern crate quick_error;
@@ -21,25 +23,34 @@ extern crate r2d2_sqlite;
 extern crate rusqlite_migration;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
-extern crate sessions;
+extern crate sha1;
 extern crate sha2;
+extern crate syntect;
 extern crate time;
 extern crate toml;
+extern crate ureq;
 
 #[macro_use] mod macros;
 mod cmd;
 mod db;
 mod git;
+mod highlight;
+mod ldap;
+mod oidc;
 mod routes;
 mod templates;
+mod totp;
 mod types;
 
 use clap::{Command, Arg};
 
+use std::collections::HashMap;
 use std::fs;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::time::Instant;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
@@ -58,13 +69,19 @@ quick_error! {
         Git(err: ::git2::Error) {
             from()
         }
+        Http(err: Box<::ureq::Error>) {
+            from()
+        }
         Io(err: ::std::io::Error) {
             from()
         }
-        R2D2(err: ::r2d2::Error) {
+        Json(err: ::serde_json::Error) {
+            from()
+        }
+        Ldap(err: Box<::ldap3::LdapError>) {
             from()
         }
-        Session(err: sessions::Error) {
+        R2D2(err: ::r2d2::Error) {
             from()
         }
         Sqlite(err: rusqlite::Error) {
@@ -76,11 +93,33 @@ quick_error! {
 pub struct Context {
     pub db_pool: db::Pool,
     pub mount: String,
-    pub logins: Arc<Mutex<sessions::SessionSet>>,
+    /// Logins that passed the password check and are waiting for a second
+    /// factor, keyed by the `pending_login` cookie.
+    pub pending_logins: Mutex<HashMap<String, types::PendingLogin>>,
+    /// TOTP secrets waiting to be confirmed with a code, keyed by user id. They
+    /// are kept here so that users can't pick their own secret.
+    pub pending_totp: Mutex<HashMap<i32, types::PendingTotp>>,
     pub name: String,
     pub url: String,
     pub ssh: String,
-    pub signup: bool,
+    /// Whether open signup is enabled, this can be toggled by admins at runtime.
+    pub signup: AtomicBool,
+    /// Email domains allowed to use open signup, empty allows any domain.
+    pub signup_domains: Vec<String>,
+    pub proxy_auth: Option<ProxyAuth>,
+    pub oidc: Option<OidcProvider>,
+    pub ldap: Option<LdapDirectory>,
+    /// Logins waiting for the OpenID Connect provider to redirect back, keyed
+    /// by their state.
+    pub oidc_logins: Mutex<HashMap<String, types::PendingOidc>>,
+    /// Identities waiting for a password to be linked to an existing account,
+    /// keyed by the `oidc_link` cookie.
+    pub oidc_links: Mutex<HashMap<String, types::PendingOidcLink>>,
+    pub highlighter: highlight::Highlighter,
+    /// Shared with the thread counting commits in the background.
+    pub commit_counts: Arc<Mutex<types::CommitCounts>>,
+    /// The size of `repo_dir` and when it was measured.
+    pub disk_usage: Mutex<Option<(Instant, u64)>>,
     pub repo_dir: PathBuf,
     pub ssh_dir: PathBuf,
     pub bin_path: PathBuf,
@@ -91,14 +130,66 @@ pub struct Context {
 pub struct Config {
     pub repo_dir: PathBuf,
     pub ssh_dir: Option<PathBuf>,
-    pub sessions_dir: PathBuf,
     pub db_path: PathBuf,
     pub mount: Option<String>,
     pub name: Option<String>,
     pub url: Option<String>,
     pub ssh: String,
     pub signup: Option<bool>,
+    pub signup_domains: Option<Vec<String>>,
     pub addr: Option<SocketAddr>,
+    pub proxy_auth: Option<ProxyAuth>,
+    pub oidc: Option<OidcProvider>,
+    pub ldap: Option<LdapDirectory>,
+}
+
+/// Authentication by an SSO reverse proxy that passes the logged in user in a
+/// header. The header is only trusted on requests coming from `proxies`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ProxyAuth {
+    /// The header holding the username, e.g. `X-Remote-User`
+    pub header: String,
+    /// The header holding the email address, used when creating new users
+    pub email_header: Option<String>,
+    pub proxies: Vec<IpAddr>,
+}
+
+/// An OpenID Connect provider users can log in with.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OidcProvider {
+    /// The name shown on the login button
+    pub name: Option<String>,
+    pub issuer: String,
+    pub client_id: String,
+    /// Public clients only use PKCE and have no secret
+    pub client_secret: Option<String>,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+}
+
+/// An LDAP directory users can log in with.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LdapDirectory {
+    pub url: String,
+    /// The account used to search for users, searches are anonymous without it
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub base_dn: String,
+    /// Finds the entry of a user, `{username}` is replaced with the username
+    pub user_filter: String,
+    /// The attribute new accounts are named after, defaults to `uid`
+    pub username_attribute: Option<String>,
+    /// Defaults to `mail`
+    pub email_attribute: Option<String>,
+    /// The attribute listing the groups of a user, defaults to `memberOf`
+    pub group_attribute: Option<String>,
+    /// Members of this group are site admins
+    pub admin_group: Option<String>,
+    /// Members of these groups are added to the organization they map to
+    pub org_groups: Option<HashMap<String, String>>,
+    /// How often users are checked against the directory, defaults to an hour
+    pub sync_minutes: Option<u64>,
 }
 
 fn main() {
@@ -119,6 +210,15 @@ fn main() {
                          .help("The file to output the backup to e.g. val.tgz")
                          .required(true)
                          .index(1)))
+        .subcommand(Command::new("hook")
+                    .about("Git hook used to enforce repository rules. Not intended to be used directly")
+                    .arg(Arg::new("HOOK")
+                         .help("The name of the hook being run")
+                         .required(true)
+                         .index(1))
+                    .arg(Arg::new("KEYID")
+                         .help("The id of the ssh key or user that is pushing")
+                         .index(2)))
         .subcommand(Command::new("ssh")
                     .about("Command used for ssh. Not intended to be used directly")
                     .arg(Arg::new("KEYID")
@@ -141,6 +241,8 @@ fn main() {
     if let Some(matches) = matches.subcommand_matches("backup") {
         let file = matches.get_one::<String>("FILE").unwrap();
         cmd::backup::run(file);
+    } else if let Some(matches) = matches.subcommand_matches("hook") {
+        cmd::hook::run(config, matches);
     } else if let Some(matches) = matches.subcommand_matches("ssh") {
         cmd::ssh::run(config, matches);
     } else if let Some(_matches) = matches.subcommand_matches("web") {
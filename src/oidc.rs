@@ -0,0 +1,358 @@
+//! Logging in with an OpenID Connect provider using the authorization code
+//! flow with PKCE.
+//!
+//! The ID token is received directly from the provider's token endpoint, so
+//! its claims are checked but its signature isn't, as allowed by OpenID
+//! Connect Core 3.1.3.7.
+
+use {Error, OidcProvider, Result};
+use types::PendingOidc;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::{self, Rng};
+use rand::distributions::Alphanumeric;
+use serde_json::{self, Value};
+use sha2::{Digest, Sha256};
+use ureq;
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long the user has to log in at the provider.
+const PENDING_SECS: u64 = 600;
+
+/// The user as identified by the provider.
+pub struct Identity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub username: Option<String>,
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+fn url_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+/// Starts a login, returning the url to send the user to and the state the
+/// provider will send back along with the pending login.
+pub fn authorize(provider: &OidcProvider, redirect_uri: &str) -> (String, String, PendingOidc) {
+    let state = random_string(32);
+    let pending = PendingOidc {
+        verifier: random_string(64),
+        nonce: random_string(32),
+        expires: Instant::now() + Duration::from_secs(PENDING_SECS),
+    };
+    let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(pending.verifier.as_bytes()));
+
+    let separator = if provider.authorization_endpoint.contains('?') { '&' } else { '?' };
+    let url = format!("{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&nonce={}\
+                       &code_challenge={}&code_challenge_method=S256",
+                      provider.authorization_endpoint, separator, url_encode(&provider.client_id),
+                      url_encode(redirect_uri), url_encode("openid email profile"), state,
+                      pending.nonce, challenge);
+    (url, state, pending)
+}
+
+/// Reads the claims of an ID token without checking its signature.
+fn id_token_claims(token: &str) -> Option<Value> {
+    let payload = try_opt!(token.split('.').nth(1));
+    let payload = try_opt!(URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok());
+    serde_json::from_slice(&payload).ok()
+}
+
+fn valid_claims(provider: &OidcProvider, claims: &Value, nonce: &str) -> bool {
+    let audience = match claims["aud"] {
+        Value::String(ref aud) => aud == &provider.client_id,
+        Value::Array(ref auds) => auds.iter().any(|aud| aud.as_str() == Some(&provider.client_id)),
+        _ => false,
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let issuer = claims["iss"].as_str().map(|iss| iss.trim_end_matches('/'));
+
+    issuer == Some(provider.issuer.trim_end_matches('/')) && audience &&
+        claims["exp"].as_u64().map_or(false, |exp| exp > now) &&
+        claims["nonce"].as_str() == Some(nonce)
+}
+
+/// Sends a request to the provider, returning `None` if it rejected it.
+fn send(request: ::std::result::Result<ureq::Response, ureq::Error>) -> Result<Option<Value>> {
+    match request {
+        Ok(response) => Ok(Some(serde_json::from_str(&response.into_string()?)?)),
+        Err(ureq::Error::Status(code, _)) => {
+            warn!("OpenID Connect provider responded with {}", code);
+            Ok(None)
+        }
+        Err(e) => Err(Error::from(Box::new(e))),
+    }
+}
+
+/// Exchanges the code the provider sent back for the identity of the user.
+/// Returns `None` if the provider didn't accept the code or sent back
+/// invalid tokens.
+pub fn exchange(provider: &OidcProvider, redirect_uri: &str, code: &str, pending: &PendingOidc)
+    -> Result<Option<Identity>>
+{
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri),
+        ("client_id", provider.client_id.as_str()),
+        ("code_verifier", pending.verifier.as_str()),
+    ];
+    if let Some(ref secret) = provider.client_secret {
+        form.push(("client_secret", secret.as_str()));
+    }
+    let tokens = match send(ureq::post(&provider.token_endpoint).send_form(&form))? {
+        Some(tokens) => tokens,
+        None => return Ok(None),
+    };
+
+    let claims = match tokens["id_token"].as_str().and_then(id_token_claims) {
+        Some(claims) => claims,
+        None => return Ok(None),
+    };
+    if !valid_claims(provider, &claims, &pending.nonce) {
+        warn!("OpenID Connect provider sent an invalid ID token");
+        return Ok(None);
+    }
+    let (subject, access_token) = match (claims["sub"].as_str(), tokens["access_token"].as_str()) {
+        (Some(sub), Some(token)) => (sub, token),
+        _ => return Ok(None),
+    };
+
+    let request = ureq::get(&provider.userinfo_endpoint)
+        .set("Authorization", &format!("Bearer {}", access_token))
+        .call();
+    let info = match send(request)? {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+    // The user info has to be about the user the ID token was issued to
+    if info["sub"].as_str() != Some(subject) {
+        return Ok(None);
+    }
+
+    // Some providers send the flag as a string
+    let email_verified = match info["email_verified"] {
+        Value::Bool(verified) => verified,
+        Value::String(ref verified) => verified == "true",
+        _ => false,
+    };
+    Ok(Some(Identity {
+        subject: subject.to_string(),
+        email: info["email"].as_str().map(|e| e.to_string()),
+        email_verified: email_verified,
+        username: info["preferred_username"].as_str().map(|u| u.to_string()),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    const NONCE: &str = "n-0S6_WzA2Mj";
+
+    fn provider(base: &str) -> OidcProvider {
+        OidcProvider {
+            name: None,
+            issuer: String::from("https://id.example.com"),
+            client_id: String::from("valentine"),
+            client_secret: Some(String::from("secret")),
+            authorization_endpoint: format!("{}/authorize", base),
+            token_endpoint: format!("{}/token", base),
+            userinfo_endpoint: format!("{}/userinfo", base),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn claims(aud: Value, iss: &str, exp: u64, nonce: &str) -> Value {
+        let mut claims: Value = serde_json::from_str("{}").unwrap();
+        claims["sub"] = Value::from("248289761001");
+        claims["aud"] = aud;
+        claims["iss"] = Value::from(iss);
+        claims["exp"] = Value::from(exp);
+        claims["nonce"] = Value::from(nonce);
+        claims
+    }
+
+    fn id_token(claims: &Value) -> String {
+        format!("{}.{}.{}", URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256"}"#),
+                URL_SAFE_NO_PAD.encode(claims.to_string()), URL_SAFE_NO_PAD.encode("signature"))
+    }
+
+    #[test]
+    fn claims_valid() {
+        let provider = provider("http://localhost");
+        let valid = claims(Value::from("valentine"), "https://id.example.com", now() + 60, NONCE);
+        assert!(valid_claims(&provider, &valid, NONCE));
+    }
+
+    #[test]
+    fn claims_audience_array() {
+        let provider = provider("http://localhost");
+        let aud = Value::from(vec!["other", "valentine"]);
+        assert!(valid_claims(&provider, &claims(aud, "https://id.example.com", now() + 60, NONCE), NONCE));
+        let aud = Value::from(vec!["other"]);
+        assert!(!valid_claims(&provider, &claims(aud, "https://id.example.com", now() + 60, NONCE), NONCE));
+    }
+
+    #[test]
+    fn claims_issuer_trailing_slash() {
+        let mut provider = provider("http://localhost");
+        let aud = Value::from("valentine");
+        assert!(valid_claims(&provider, &claims(aud.clone(), "https://id.example.com/", now() + 60, NONCE),
+                             NONCE));
+        provider.issuer.push('/');
+        assert!(valid_claims(&provider, &claims(aud.clone(), "https://id.example.com", now() + 60, NONCE),
+                             NONCE));
+        assert!(!valid_claims(&provider, &claims(aud, "https://evil.example.com", now() + 60, NONCE), NONCE));
+    }
+
+    #[test]
+    fn claims_expired() {
+        let provider = provider("http://localhost");
+        let expired = claims(Value::from("valentine"), "https://id.example.com", now() - 1, NONCE);
+        assert!(!valid_claims(&provider, &expired, NONCE));
+    }
+
+    #[test]
+    fn claims_nonce_mismatch() {
+        let provider = provider("http://localhost");
+        let valid = claims(Value::from("valentine"), "https://id.example.com", now() + 60, NONCE);
+        assert!(!valid_claims(&provider, &valid, "other"));
+    }
+
+    #[test]
+    fn token_claims() {
+        let expected = claims(Value::from("valentine"), "https://id.example.com", 1311281970, NONCE);
+        assert_eq!(id_token_claims(&id_token(&expected)), Some(expected.clone()));
+        // Padding isn't allowed in JWTs but some providers send it anyway
+        let padded = format!("a.{}==.c", URL_SAFE_NO_PAD.encode(expected.to_string()));
+        assert_eq!(id_token_claims(&padded), Some(expected));
+
+        assert_eq!(id_token_claims("no-dots"), None);
+        assert_eq!(id_token_claims("a.!!!.c"), None);
+        assert_eq!(id_token_claims(&format!("a.{}.c", URL_SAFE_NO_PAD.encode("not json"))), None);
+    }
+
+    /// Answers `requests` HTTP requests on a local port with the response for
+    /// their path, returning the base url and the requests it got.
+    fn stub(responses: Vec<(&'static str, String)>, requests: usize)
+        -> (String, thread::JoinHandle<Vec<String>>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut received = Vec::new();
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.to_lowercase().starts_with("content-length:") {
+                        length = line[15..].trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    request.push_str(&line);
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+
+                let path = request.split_whitespace().nth(1).unwrap_or("").to_string();
+                let body = responses.iter().find(|r| r.0 == path).map(|r| r.1.clone());
+                let response = match body {
+                    Some(body) => format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                                           Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                          body.len(), body),
+                    None => String::from("HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\
+                                          Connection: close\r\n\r\n"),
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+                received.push(request);
+            }
+            received
+        });
+        (base, handle)
+    }
+
+    fn pending() -> PendingOidc {
+        PendingOidc {
+            verifier: String::from("verifier"),
+            nonce: String::from(NONCE),
+            expires: Instant::now() + Duration::from_secs(60),
+        }
+    }
+
+    #[test]
+    fn exchange_code() {
+        let claims = claims(Value::from("valentine"), "https://id.example.com", now() + 60, NONCE);
+        let tokens = format!(r#"{{"id_token":"{}","access_token":"access"}}"#, id_token(&claims));
+        let info = String::from(r#"{"sub":"248289761001","email":"jane@example.com",
+                                    "email_verified":"true","preferred_username":"jane"}"#);
+        let (base, handle) = stub(vec![("/token", tokens), ("/userinfo", info)], 2);
+
+        let identity = exchange(&provider(&base), "https://git.example.com/login/oidc/callback",
+                                "code", &pending()).unwrap().unwrap();
+        assert_eq!(identity.subject, "248289761001");
+        assert_eq!(identity.email.as_ref().map(|e| e.as_str()), Some("jane@example.com"));
+        assert!(identity.email_verified);
+        assert_eq!(identity.username.as_ref().map(|u| u.as_str()), Some("jane"));
+
+        let requests = handle.join().unwrap();
+        assert!(requests[0].starts_with("POST /token "));
+        assert!(requests[0].contains("code_verifier=verifier"));
+        assert!(requests[0].contains("client_secret=secret"));
+        assert!(requests[1].starts_with("GET /userinfo "));
+        assert!(requests[1].contains("Bearer access"));
+    }
+
+    #[test]
+    fn exchange_rejected_code() {
+        let (base, handle) = stub(vec![], 1);
+        let identity = exchange(&provider(&base), "https://git.example.com/login/oidc/callback",
+                                "code", &pending()).unwrap();
+        assert!(identity.is_none());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn exchange_other_subject() {
+        let claims = claims(Value::from("valentine"), "https://id.example.com", now() + 60, NONCE);
+        let tokens = format!(r#"{{"id_token":"{}","access_token":"access"}}"#, id_token(&claims));
+        let info = String::from(r#"{"sub":"someone-else","email":"jane@example.com"}"#);
+        let (base, handle) = stub(vec![("/token", tokens), ("/userinfo", info)], 2);
+
+        let identity = exchange(&provider(&base), "https://git.example.com/login/oidc/callback",
+                                "code", &pending()).unwrap();
+        assert!(identity.is_none());
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+use {db, git, ldap};
+use templates::*;
+use super::{not_found, util};
+
+use humansize::{format_size, DECIMAL};
+
+use std::sync::atomic::Ordering;
+
+/// Only admins may use the admin area, everyone else gets a 404.
+macro_rules! check_admin {
+    ( $req:ident, $res:ident, $ctx:ident ) => {
+        {
+            let cookies = $req.get_cookies();
+            match util::current_user($req, $ctx, &cookies) {
+                Some(user) if user.is_admin => user,
+                _ => return not_found($req, $res, $ctx),
+            }
+        }
+    };
+}
+
+// GET /admin
+route!{panel, req, res, ctx, {
+    let current = check_admin!(req, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let users = db::read::admin_users(pool)?;
+    let repos = db::read::admin_repos(pool)?;
+    let invites = db::read::invites(pool, None)?;
+    let lockouts = db::read::lockouts(pool)?;
+    let auth_events = db::read::auth_events(pool, 100)?;
+    let body = AdminTmpl {
+        mount: &ctx.mount,
+        signup: ctx.signup.load(Ordering::Relaxed),
+        ldap: ctx.ldap.is_some(),
+        num_users: users.iter().filter(|u| !u.is_org).count(),
+        num_orgs: users.iter().filter(|u| u.is_org).count(),
+        num_repos: repos.len(),
+        disk_usage: format_size(git::disk_usage(ctx)?, DECIMAL),
+        users: users,
+        repos: repos,
+        invites: invites,
+        lockouts: lockouts,
+        auth_events: auth_events,
+        csrf: util::csrf_token(&req.get_cookies()),
+    };
+
+    let navbar = Navbar::new(ctx, Some(&current));
+    tmpl!(res, ctx, Some("Admin"), Some(navbar), None, body);
+}}
+
+// POST /admin/signup
+route!{toggle_signup, req, res, ctx, {
+    check_admin!(req, res, ctx);
+    check_csrf!(req, &req.get_cookies(), res, ctx);
+
+    let signup = !ctx.signup.load(Ordering::Relaxed);
+    db::update::site_setting(&ctx.db_pool, "signup", if signup { "true" } else { "false" })?;
+    ctx.signup.store(signup, Ordering::Relaxed);
+    redirect!(res, ctx, "admin", if signup { "Signup enabled" } else { "Signup disabled" });
+}}
+
+// POST /admin/users/{id}/disable
+route!{toggle_user, req, res, ctx, {
+    let current = check_admin!(req, res, ctx);
+    check_csrf!(req, &req.get_cookies(), res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i32);
+
+    let pool = &ctx.db_pool;
+    if current.id == id {
+        redirect!(res, ctx, "admin", "You can't disable yourself");
+    }
+
+    let disabled = match db::read::user_disabled(pool, id)? {
+        Some(disabled) => !disabled,
+        None => return not_found(req, res, ctx),
+    };
+    db::update::user_disabled(pool, id, disabled)?;
+    if disabled {
+        db::delete::sessions(pool, id, None)?;
+    }
+    redirect!(res, ctx, "admin", if disabled { "User disabled" } else { "User enabled" });
+}}
+
+// POST /admin/users/{id}/ldap
+route!{link_ldap, req, res, ctx, {
+    check_admin!(req, res, ctx);
+    check_csrf!(req, &req.get_cookies(), res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i32);
+    let config = match ctx.ldap {
+        Some(ref config) => config,
+        None => return not_found(req, res, ctx),
+    };
+
+    let pool = &ctx.db_pool;
+    let name = match db::read::user_name(pool, id)? {
+        Some(name) => name,
+        None => return not_found(req, res, ctx),
+    };
+    if db::read::is_org(pool, &name)? {
+        redirect!(res, ctx, "admin", "Organizations can't be linked to the directory");
+    }
+    if !ldap::link_user(ctx, config, id, &name)? {
+        redirect!(res, ctx, "admin", "No unlinked directory user has that name");
+    }
+    redirect!(res, ctx, "admin", "User linked to the directory");
+}}
+
+// POST /admin/users/{id}/delete
+route!{delete_user, req, res, ctx, {
+    let current = check_admin!(req, res, ctx);
+    check_csrf!(req, &req.get_cookies(), res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i32);
+
+    let pool = &ctx.db_pool;
+    if current.id == id {
+        redirect!(res, ctx, "admin", "You can't delete yourself");
+    }
+
+    let name = match db::read::user_name(pool, id)? {
+        Some(name) => name,
+        None => return not_found(req, res, ctx),
+    };
+    if util::sole_owned_org(pool, id)?.is_some() {
+        redirect!(res, ctx, "admin",
+                  "That user is the only owner of an organization, add another owner or delete it first");
+    }
+    git::delete_ssh_keys(ctx, &db::read::public_key_ids(pool, id)?)?;
+    db::delete::user(pool, &name)?;
+    git::delete_user(ctx, &name)?;
+    redirect!(res, ctx, "admin", "User deleted");
+}}
+
+// POST /admin/repos/{id}/delete
+route!{delete_repo, req, res, ctx, {
+    check_admin!(req, res, ctx);
+    check_csrf!(req, &req.get_cookies(), res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    let pool = &ctx.db_pool;
+    let (owner, name) = if let Some(repo) = db::read::repo_by_id(pool, id)? {
+        repo
+    } else {
+        return not_found(req, res, ctx);
+    };
+
+    db::delete::repo(pool, &owner, &name)?;
+    git::delete(ctx, &owner, &name)?;
+    redirect!(res, ctx, "admin", "Repo deleted");
+}}
+
+// POST /admin/repos/{id}/transfer
+route!{transfer_repo, req, res, ctx, {
+    check_admin!(req, res, ctx);
+    check_csrf!(req, &req.get_cookies(), res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i64);
+
+    let pool = &ctx.db_pool;
+    let (owner, name) = if let Some(repo) = db::read::repo_by_id(pool, id)? {
+        repo
+    } else {
+        return not_found(req, res, ctx);
+    };
+
+    let new_owner = req.form_value("owner").unwrap_or_default();
+    if !db::read::user_exists(pool, &new_owner)? {
+        redirect!(res, ctx, "admin", "New owner does not exist");
+    }
+    if db::read::repo_exists(pool, &new_owner, &name)? {
+        redirect!(res, ctx, "admin", "New owner already has a repo with that name");
+    }
+
+    git::transfer(ctx, &owner, &new_owner, &name)?;
+    let new_owner_id = db::read::user_id(pool, &new_owner)?;
+    if let Err(e) = db::update::transfer_repo(pool, id, new_owner_id) {
+        // Put the repo back so that the database and disk stay consistent
+        git::transfer(ctx, &new_owner, &owner, &name)?;
+        return Err(e);
+    }
+    redirect!(res, ctx, "admin", "Repo transferred");
+}}
+
+// POST /admin/invites/{id}/delete
+route!{delete_invite, req, res, ctx, {
+    check_admin!(req, res, ctx);
+    check_csrf!(req, &req.get_cookies(), res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i32);
+
+    db::delete::invite(&ctx.db_pool, id)?;
+    redirect!(res, ctx, "admin", "Invitation revoked");
+}}
+
+// POST /admin/lockouts/{id}/clear
+route!{clear_lockout, req, res, ctx, {
+    check_admin!(req, res, ctx);
+    check_csrf!(req, &req.get_cookies(), res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i32);
+
+    db::delete::throttle_by_id(&ctx.db_pool, id)?;
+    redirect!(res, ctx, "admin", "Lockout cleared");
+}}
@@ -1,21 +1,80 @@
-use {db, git};
-use super::not_found;
+use {db, git, Context, Result};
+use types::Access;
+use super::{not_found, throttle, util};
 
-use hayaku::{header, Status};
+use hayaku::{header, Response, Status};
+
+/// Resolves the user making a git request from HTTP Basic credentials and
+/// checks that they have at least `$required` access to the repo. Anonymous
+/// requests that lack access are asked to authenticate.
+macro_rules! git_access {
+    ( $username:expr, $reponame:expr, $required:expr, $req:ident, $res:ident, $ctx:ident ) => {
+        {
+            let pool = &$ctx.db_pool;
+            if !db::read::user_exists(pool, &$username)? ||
+               !db::read::repo_exists(pool, &$username, &$reponame)?
+            {
+                return not_found($req, $res, $ctx);
+            }
+
+            let user = if let Some(login) = util::basic_auth($req) {
+                let ip = util::remote_ip($req);
+                if let Some(wait) = throttle::check($ctx, ip, &login.username)? {
+                    return too_many_attempts($res, wait);
+                }
+                let user = match util::check_password($ctx, &login)? {
+                    Some(user) => user,
+                    None => {
+                        throttle::failure($ctx, ip, &login.username, throttle::Source::Git)?;
+                        return unauthorized($res, $ctx);
+                    }
+                };
+                throttle::success($ctx, &login.username)?;
+
+                // A password alone isn't enough for accounts with two-factor
+                // authentication, they have to use ssh
+                if db::read::totp(pool, user)?.is_some() {
+                    return two_factor_required($res);
+                }
+                Some(user)
+            } else {
+                None
+            };
+
+            let access = db::read::access(pool, user, &$username, &$reponame)?;
+            if access < $required {
+                if user.is_none() {
+                    return unauthorized($res, $ctx);
+                }
+                return not_found($req, $res, $ctx);
+            }
+            user
+        }
+    };
+}
+
+fn unauthorized(res: &mut Response, ctx: &Context) -> Result<()> {
+    res.status(Status::UNAUTHORIZED);
+    res.add_header(header::WWW_AUTHENTICATE, hval!("Basic realm=\"Valentine\""));
+    Ok(res.body(format!("You must authenticate with {} to access this repository.", ctx.name)))
+}
+
+fn two_factor_required(res: &mut Response) -> Result<()> {
+    res.status(Status::FORBIDDEN);
+    Ok(res.body("This account uses two-factor authentication, please use ssh to access repositories."))
+}
+
+fn too_many_attempts(res: &mut Response, wait: i64) -> Result<()> {
+    res.status(Status::TOO_MANY_REQUESTS);
+    res.add_header(header::RETRY_AFTER, header::HeaderValue::from(wait));
+    Ok(res.body("Too many failed authentication attempts, please try again later."))
+}
 
 // GET /{user}/{repo}/info/refs
 route!{pull_handshake, req, res, ctx, {
     let username = req.get_param("user");
     let repo = req.get_param("repo");
-
-    let pool = &ctx.db_pool;
-    // Make sure that repo exists
-    let repo_name = repo.trim_end_matches(".git");
-    if !db::read::repo_exists(pool, &username, repo_name)? ||
-       db::read::repo_is_private(pool, &username, repo_name)?
-    {
-        return not_found(req, res, ctx);
-    }
+    let repo_name = repo.trim_end_matches(".git").to_string();
 
     let mode = if let Some(verb) = req.form_value("service") {
         if let Some(mode) = git::AccessMode::new(&verb) {
@@ -30,21 +89,31 @@ route!{pull_handshake, req, res, ctx, {
         return Ok(res.body(body));
     };
 
-    if mode == git::AccessMode::Write {
-        res.status(Status::FORBIDDEN);
-        let body = format!("{} does not support git-receive-pack over HTTP.", ctx.name);
-        return Ok(res.body(body));
+    // git follows redirects on this first request and uses the new location
+    // for the rest of the exchange
+    if let Some((owner, name)) = db::read::repo_redirect(&ctx.db_pool, &username, &repo_name)? {
+        git_access!(owner, name, Access::Read, req, res, ctx);
+        let service = req.form_value("service").unwrap_or_default();
+        redirect!(res, ctx, format!("{}/{}/info/refs?service={}", owner, name, service), "Repository moved");
     }
 
-    let packet = "# service=git-upload-pack\n";
+    let (service, content_type) = if mode == git::AccessMode::Write {
+        git_access!(username, repo_name, Access::Write, req, res, ctx);
+        ("git-receive-pack", hval!("application/x-git-receive-pack-advertisement"))
+    } else {
+        git_access!(username, repo_name, Access::Read, req, res, ctx);
+        ("git-upload-pack", hval!("application/x-git-upload-pack-advertisement"))
+    };
+
+    let packet = format!("# service={}\n", service);
     let length = packet.len() + 4;
     let prefix = format!("{:04x}{}0000", length, packet);
 
-    let mut pack = git::network::info(ctx, &username, &repo)?;
+    let mut pack = git::network::info(ctx, &username, &repo_name, service)?;
     res.add_header(header::EXPIRES, hval!("Fri, 01 Jan 1980 00:00:00 GMT"));
     res.add_header(header::PRAGMA, hval!("no-cache"));
     res.add_header(header::CACHE_CONTROL, hval!("no-cache, max-age=0, must-revalidate"));
-    res.add_header(header::CONTENT_TYPE, hval!("application/x-git-upload-pack-advertisement"));
+    res.add_header(header::CONTENT_TYPE, content_type);
 
     // Build body
     let mut body = Vec::new();
@@ -57,11 +126,34 @@ route!{pull_handshake, req, res, ctx, {
 route!{pull, req, res, ctx, {
     let username = req.get_param("user");
     let repo = req.get_param("repo");
+    let repo_name = repo.trim_end_matches(".git").to_string();
 
-    let pack = git::network::pull(ctx, &username, &repo, &req.body())?;
+    git_access!(username, repo_name, Access::Read, req, res, ctx);
+
+    let pack = git::network::pull(ctx, &username, &repo_name, &req.body())?;
     res.add_header(header::EXPIRES, hval!("Fri, 01 Jan 1980 00:00:00 GMT"));
     res.add_header(header::PRAGMA, hval!("no-cache"));
     res.add_header(header::CACHE_CONTROL, hval!("no-cache, max-age=0, must-revalidate"));
     res.add_header(header::CONTENT_TYPE, hval!("application/x-git-upload-pack-result"));
     Ok(res.body(pack))
 }}
+
+// POST /{user}/{repo}/git-receive-pack
+route!{push, req, res, ctx, {
+    let username = req.get_param("user");
+    let repo = req.get_param("repo");
+    let repo_name = repo.trim_end_matches(".git").to_string();
+
+    let user = match git_access!(username, repo_name, Access::Write, req, res, ctx) {
+        Some(user) => user,
+        None => return unauthorized(res, ctx),
+    };
+
+    let pack = git::network::push(ctx, &username, &repo_name, user, &req.body())?;
+    db::update::repo(&ctx.db_pool, &username, &repo_name)?;
+    res.add_header(header::EXPIRES, hval!("Fri, 01 Jan 1980 00:00:00 GMT"));
+    res.add_header(header::PRAGMA, hval!("no-cache"));
+    res.add_header(header::CACHE_CONTROL, hval!("no-cache, max-age=0, must-revalidate"));
+    res.add_header(header::CONTENT_TYPE, hval!("application/x-git-receive-pack-result"));
+    Ok(res.body(pack))
+}}
@@ -1,47 +1,69 @@
+pub mod admin;
 pub mod git_routes;
 pub mod repo;
 pub mod user;
+pub mod throttle;
 mod util;
 
 use {Context, Error, db};
 use templates::*;
 
-use hayaku::{Request, Response, Status};
+use hayaku::{header, Request, Response, Status};
 
 // GET /
 route!{home, req, res, ctx, {
     let cookies = &req.get_cookies();
-    let username = util::check_login(ctx, cookies);
-    let navbar = Navbar::new(ctx, username);
-    tmpl!(res, ctx, username, Some(navbar), None, HomeTmpl);
+    let current = util::current_user(req, ctx, cookies);
+    let navbar = Navbar::new(ctx, current.as_ref());
+    tmpl!(res, ctx, current.as_ref().map(|u| u.username.as_str()), Some(navbar), None, HomeTmpl);
 }}
 
 // GET /explore
 route!{explore, req, res, ctx, {
     let cookies = &req.get_cookies();
-    let username = util::check_login(ctx, cookies);
-    let navbar = Navbar::new(ctx, username);
+    let current = util::current_user(req, ctx, cookies);
+    let navbar = Navbar::new(ctx, current.as_ref());
     let pool = &ctx.db_pool;
-    let users = db::read::users(pool, &ctx)?;
-    tmpl!(res, ctx, username, Some(navbar), None, users);
+    let users = db::read::users(pool, &ctx, current.is_some())?;
+    tmpl!(res, ctx, current.as_ref().map(|u| u.username.as_str()), Some(navbar), None, users);
 }}
 
 // GET /{user}
 route!{user, req, res, ctx, {
     let cookies = req.get_cookies();
-    let username = util::check_login(ctx, &cookies);
+    let current = util::current_user(req, ctx, &cookies);
     let user = req.get_param("user");
 
-    let auth = if let Some(username) = username { username == user } else { false};
     let pool = &ctx.db_pool;
-    if let Some(mut body) = db::read::user(pool, &user, &ctx, auth)? {
-        let navbar = Navbar::new(ctx, username);
+    if !db::read::user_exists(pool, &user)? {
+        return not_found(req, res, ctx);
+    }
+
+    // Private repos are shown to the user themselves and to organization members
+    let auth = if let Some(ref current) = current {
+        if current.username == user {
+            true
+        } else {
+            let owner = db::read::user_id(pool, &user)?;
+            db::read::org_role(pool, owner, current.id)?.is_some()
+        }
+    } else {
+        false
+    };
+    if let Some(mut body) = db::read::user(pool, &user, &ctx, current.is_some(), auth)? {
+        let navbar = Navbar::new(ctx, current.as_ref());
         tmpl!(res, ctx, Some(&user), Some(navbar), None, body);
     } else {
         not_found(req, res, ctx)
     }
 }}
 
+// GET /highlight.css
+route!{highlight_css, req, res, ctx, {
+    res.add_header(header::CONTENT_TYPE, hval!("text/css; charset=utf-8"));
+    ok!(res.body(ctx.highlighter.css.clone()));
+}}
+
 route!{not_found, req, res, ctx, {
     res.status(Status::NOT_FOUND);
     let body = include_str!("../../templates/404.html");
@@ -59,7 +81,6 @@ pub fn internal_error(_req: &mut Request, res: &mut Response, ctx: &Context, err
         Error::Git(e) => res.fmt_body(format!("Git error: {}", e)),
         Error::Io(e) => res.fmt_body(format!("Io error: {}", e)),
         Error::R2D2(e) => res.fmt_body(format!("R2D2 error: {}", e)),
-        Error::Session(e) => res.fmt_body(format!("Session error: {}", e)),
         Error::Sqlite(e) => res.fmt_body(format!("Sqlite error: {}", e)),
         */
         _ => {
@@ -1,6 +1,6 @@
 use {db, git};
 use templates::*;
-use types::RepoSrc;
+use types::{Access, RepoSrc, Visibility};
 use super::{not_found, util};
 
 use hayaku::header;
@@ -11,20 +11,24 @@ macro_rules! read_repo {
             let pool = &$ctx.db_pool;
             let repo = if let Some(repo) = db::read::repo(pool, &$username, &$reponame)? {
                 repo
+            } else if let Some((owner, name)) = db::read::repo_redirect(pool, &$username, &$reponame)? {
+                // Transferred repos redirect to their new location for
+                // anyone who can see them there
+                let cookies = $req.get_cookies();
+                let user = util::current_user($req, $ctx, &cookies).map(|u| u.id);
+                if db::read::access(pool, user, &owner, &name)? == Access::None {
+                    return not_found($req, $res, $ctx);
+                }
+                redirect!($res, $ctx, format!("{}/{}", owner, name), "This repository has moved");
             } else {
                 return not_found($req, $res, $ctx);
             };
 
-            // Check if private repo can be viewed by this request
-            if repo.private {
+            // Check if a non-public repo can be viewed by this request
+            if repo.visibility != Visibility::Public {
                 let cookies = $req.get_cookies();
-                // Private repos can only be viewed by logged in users
-                if let Some(username) = util::check_login($ctx, &cookies) {
-                    let owner = db::read::user_id(pool, username)?;
-                    if !db::read::user_owns_repo(pool, owner, &$reponame)? {
-                        return not_found($req, $res, $ctx);
-                    }
-                } else {
+                let user = util::current_user($req, $ctx, &cookies).map(|u| u.id);
+                if db::read::access(pool, user, &$username, &$reponame)? == Access::None {
                     return not_found($req, $res, $ctx);
                 }
             }
@@ -42,8 +46,8 @@ route!{view, req, res, ctx, {
     let repo_git = git::read(ctx, &username, repo)?;
 
     let cookies = &req.get_cookies();
-    let username = util::check_login(ctx, cookies);
-    let navbar = Navbar::new(ctx, username);
+    let current = util::current_user(req, ctx, cookies);
+    let navbar = Navbar::new(ctx, current.as_ref());
 
     tmpl!(res, ctx, Some(&reponame), Some(navbar), None, repo_git);
 }}
@@ -59,13 +63,13 @@ route!{log_default, req, res, ctx, {
 route!{log, req, res, ctx, {
     let username = req.get_param("user");
     let reponame = req.get_param("repo");
-    let mut id = req.get_param("id");
+    let id = req.get_param("id");
+    let page = req.form_value("page").and_then(|p| p.parse().ok()).unwrap_or(1);
+    let after = req.form_value("after");
 
-    if let Some(s) = req.form_value("s") {
-        id = s;
-    }
     let repo = read_repo!(username, reponame, req, res, ctx);
-    let log = if let Some(log) = git::log(ctx, &username, &reponame, &id)? {
+    let log = if let Some(log) = git::log(ctx, &username, &reponame, &id, page,
+                                          after.as_ref().map(|a| a.as_str()))? {
         log
     } else {
         return not_found(req, res, ctx);
@@ -75,30 +79,116 @@ route!{log, req, res, ctx, {
         mount: &ctx.mount,
         username: &username,
         repo: repo,
-        id: req.get_param("id"),
+        id: id,
         log: log.0,
         commits: log.1,
         next: log.2,
+        // Pages reached with a cursor don't know their number
+        prev: if after.is_none() && page > 1 { Some(page - 1) } else { None },
+    };
+
+    let cookies = &req.get_cookies();
+    let current = util::current_user(req, ctx, cookies);
+    let navbar = Navbar::new(ctx, current.as_ref());
+
+    tmpl!(res, ctx, Some(&reponame), Some(navbar), None, body);
+}}
+
+// GET /{user}/{repo}/history/{id}/{*filepath}
+route!{history, req, res, ctx, {
+    let username = req.get_param("user");
+    let reponame = req.get_param("repo");
+    let id = req.get_param("id");
+    let mut filepath = req.get_param("filepath");
+    if filepath.ends_with('/') { filepath.pop(); }
+    let page = req.form_value("page").and_then(|p| p.parse().ok()).unwrap_or(1);
+    let after = req.form_value("after");
+
+    let repo = read_repo!(username, reponame, req, res, ctx);
+    let (log, next) = match git::history(ctx, &username, &reponame, &id, &filepath, page,
+                                         after.as_ref().map(|a| a.as_str()))? {
+        Some(history) => history,
+        None => return not_found(req, res, ctx),
+    };
+
+    let body = RepoHistoryTmpl {
+        mount: &ctx.mount,
+        username: &username,
+        repo: repo,
+        id: &id,
+        filename: &filepath,
+        log: log,
+        next: next,
+        // Pages reached with a cursor don't know their number
+        prev: if after.is_none() && page > 1 { Some(page - 1) } else { None },
     };
 
     let cookies = &req.get_cookies();
-    let username = util::check_login(ctx, cookies);
-    let navbar = Navbar::new(ctx, username);
+    let current = util::current_user(req, ctx, cookies);
+    let navbar = Navbar::new(ctx, current.as_ref());
 
     tmpl!(res, ctx, Some(&reponame), Some(navbar), None, body);
 }}
 
+// GET /{user}/{repo}/compare
+route!{compare_form, req, res, ctx, {
+    let username = req.get_param("user");
+    let reponame = req.get_param("repo");
+    let base = req.form_value("base").filter(|r| !r.is_empty()).unwrap_or_else(|| String::from("HEAD"));
+    let head = req.form_value("head").filter(|r| !r.is_empty()).unwrap_or_else(|| String::from("HEAD"));
+    if !url_safe_ref(&base) || !url_safe_ref(&head) {
+        redirect!(res, ctx, format!("{}/{}", username, reponame),
+                  "Refs with spaces, '..', '?', '#' or '%' can't be compared");
+    }
+    redirect!(res, ctx, format!("{}/{}/compare/{}...{}", username, reponame, base, head),
+              "Comparing refs");
+}}
+
+/// Whether `name` can be put in the compare url as is. The range is split on
+/// `...`, so names can't contain `..`, which git doesn't allow in refs anyway.
+fn url_safe_ref(name: &str) -> bool {
+    !name.contains("..") &&
+        !name.chars().any(|c| c.is_whitespace() || c.is_control() || "?#%".contains(c))
+}
+
+// GET /{user}/{repo}/compare/{base}...{head}
+route!{compare, req, res, ctx, {
+    let username = req.get_param("user");
+    let reponame = req.get_param("repo");
+    let range = req.get_param("range");
+    let (base, head) = match range.find("...") {
+        Some(i) => (&range[..i], &range[i + 3..]),
+        None => return not_found(req, res, ctx),
+    };
+
+    let repo = read_repo!(username, reponame, req, res, ctx);
+    let body = git::compare(ctx, &username, repo, base, head)?;
+    if body.is_none() {
+        return not_found(req, res, ctx);
+    }
+
+    let cookies = &req.get_cookies();
+    let current = util::current_user(req, ctx, cookies);
+    let navbar = Navbar::new(ctx, current.as_ref());
+
+    tmpl!(res, ctx, Some(&reponame), Some(navbar), None, body.unwrap());
+}}
+
 // GET /{user}/{repo}/refs
 route!{refs_list, req, res, ctx, {
     let username = req.get_param("user");
     let reponame = req.get_param("repo");
 
+    let page = req.form_value("page").and_then(|p| p.parse().ok()).unwrap_or(1);
+    let by_date = req.form_value("sort").map_or(false, |s| s == "date");
+    let filter = req.form_value("q").unwrap_or_default();
+
     let repo = read_repo!(username, reponame, req, res, ctx);
-    let body = git::refs(ctx, &username, repo)?;
+    let body = git::refs(ctx, &username, repo, page, by_date, filter)?;
 
     let cookies = &req.get_cookies();
-    let username = util::check_login(ctx, cookies);
-    let navbar = Navbar::new(ctx, username);
+    let current = util::current_user(req, ctx, cookies);
+    let navbar = Navbar::new(ctx, current.as_ref());
 
     tmpl!(res, ctx, Some(&reponame), Some(navbar), None, body);
 }}
@@ -108,16 +198,17 @@ route!{commit, req, res, ctx, {
     let username = req.get_param("user");
     let reponame = req.get_param("repo");
     let id = req.get_param("id");
+    let parent = req.form_value("parent").and_then(|p| p.parse().ok()).unwrap_or(1);
 
     let repo = read_repo!(username, reponame, req, res, ctx);
-    let body = git::commit(ctx, &username, repo, &id)?;
+    let body = git::commit(ctx, &username, repo, &id, parent)?;
     if body.is_none() {
         return not_found(req, res, ctx);
     }
 
     let cookies = &req.get_cookies();
-    let username = util::check_login(ctx, cookies);
-    let navbar = Navbar::new(ctx, username);
+    let current = util::current_user(req, ctx, cookies);
+    let navbar = Navbar::new(ctx, current.as_ref());
 
     tmpl!(res, ctx, Some(&reponame), Some(navbar), None, body.unwrap());
 }}
@@ -131,7 +222,7 @@ route!{src, req, res, ctx, {
     if filepath.ends_with('/') { filepath.pop(); }
 
     let repo = read_repo!(username, reponame, req, res, ctx);
-    let src = git::read_src(ctx, &username, &repo, &id, &filepath)?;
+    let src = git::read_src(ctx, &username, &repo, &id, &filepath, true)?;
     if src.is_none() {
         return not_found(req, res, ctx);
     }
@@ -151,18 +242,39 @@ route!{src, req, res, ctx, {
         repo: repo,
         url: format!("{}/{}/refs/{}", username, reponame, id),
         path: path,
+        refname: &id,
         // TODO: maybe something else?
         filename: &filepath,
         src: src.unwrap(),
     };
 
     let cookies = &req.get_cookies();
-    let username = util::check_login(ctx, cookies);
-    let navbar = Navbar::new(ctx, username);
+    let current = util::current_user(req, ctx, cookies);
+    let navbar = Navbar::new(ctx, current.as_ref());
 
     tmpl!(res, ctx, Some(&reponame), Some(navbar), None, body);
 }}
 
+// GET /{user}/{repo}/blame/{id}/{*filepath}
+route!{blame, req, res, ctx, {
+    let username = req.get_param("user");
+    let reponame = req.get_param("repo");
+    let id = req.get_param("id");
+    let filepath = req.get_param("filepath");
+
+    let repo = read_repo!(username, reponame, req, res, ctx);
+    let body = git::blame(ctx, &username, repo, &id, &filepath)?;
+    if body.is_none() {
+        return not_found(req, res, ctx);
+    }
+
+    let cookies = &req.get_cookies();
+    let current = util::current_user(req, ctx, cookies);
+    let navbar = Navbar::new(ctx, current.as_ref());
+
+    tmpl!(res, ctx, Some(&reponame), Some(navbar), None, body.unwrap());
+}}
+
 // GET /{user}/{repo}/refs/{id}/raw/{*filepath}
 route!{raw, req, res, ctx, {
     let username = req.get_param("user");
@@ -172,7 +284,7 @@ route!{raw, req, res, ctx, {
 
     let repo = read_repo!(username, reponame, req, res, ctx);
     // TODO: maybe just do read_file here
-    let src = match git::read_src(ctx, &username, &repo, &id, &filepath)? {
+    let src = match git::read_src(ctx, &username, &repo, &id, &filepath, false)? {
         Some(s) => s,
         None => return not_found(req, res, ctx),
     };
@@ -184,7 +296,7 @@ route!{raw, req, res, ctx, {
                       "Can't view raw directories");
         }
         RepoSrc::Error => return not_found(req, res, ctx),
-        RepoSrc::File{ file, size } => {
+        RepoSrc::File{ file, .. } => {
             res.add_header(header::CONTENT_TYPE, hval!("text/plain; charset=utf-8"));
             let body = file.iter().map(|(_, s)| s.as_str()).collect::<String>();
             let body = file.iter().map(|(_, s)| s.as_str())
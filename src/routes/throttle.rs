@@ -0,0 +1,146 @@
+//! Slows down password guessing. Failed attempts are counted per account and
+//! per IP address. After a few free attempts every further attempt has to
+//! wait exponentially longer, and enough failures lock the account or address
+//! out for a while.
+
+use {db, Context, Result};
+use db::Pool;
+use types::Throttle;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use std::cmp;
+use std::net::IpAddr;
+
+/// Failures allowed before attempts on an account start being delayed.
+const ACCOUNT_FREE_ATTEMPTS: i64 = 3;
+/// Failures allowed before attempts from an IP address start being delayed,
+/// this is higher than for accounts since many users may share an address.
+const IP_FREE_ATTEMPTS: i64 = 20;
+const MAX_DELAY_SECS: i64 = 300;
+/// Consecutive failures after which an account is locked.
+const ACCOUNT_LOCKOUT: i64 = 10;
+/// Failures after which an IP address is locked, this is higher than for
+/// accounts since many users may share an address.
+const IP_LOCKOUT: i64 = 50;
+const LOCKOUT_MINS: i64 = 15;
+/// Failures older than this are forgotten.
+const RESET_MINS: i64 = 60;
+/// How long auth events are kept for the admin panel.
+const AUTH_EVENT_DAYS: i64 = 90;
+
+/// Where the authentication attempt came from, recorded in auth events.
+#[derive(Clone, Copy)]
+pub enum Source {
+    Web,
+    Git,
+}
+
+impl Source {
+    fn name(&self) -> &'static str {
+        match *self {
+            Source::Web => "web",
+            Source::Git => "git",
+        }
+    }
+}
+
+fn keys(ip: Option<IpAddr>, username: &str) -> Vec<(&'static str, String)> {
+    let mut keys = vec![("user", username.to_lowercase())];
+    if let Some(ip) = ip {
+        keys.push(("ip", ip.to_string()));
+    }
+    keys
+}
+
+fn free_attempts(kind: &str) -> i64 {
+    if kind == "user" {
+        ACCOUNT_FREE_ATTEMPTS
+    } else {
+        IP_FREE_ATTEMPTS
+    }
+}
+
+/// Seconds until the next attempt is allowed.
+fn wait(throttle: &Throttle, free_attempts: i64, now: NaiveDateTime) -> i64 {
+    if let Some(until) = throttle.locked_until {
+        if until > now {
+            return cmp::max((until - now).num_seconds(), 1);
+        }
+    }
+
+    if throttle.failures < free_attempts || now - throttle.last_failure > Duration::minutes(RESET_MINS) {
+        return 0;
+    }
+
+    let exp = cmp::min(throttle.failures - free_attempts, 16);
+    let delay = cmp::min(1 << exp, MAX_DELAY_SECS);
+    let next = throttle.last_failure + Duration::seconds(delay);
+    if next > now {
+        cmp::max((next - now).num_seconds(), 1)
+    } else {
+        0
+    }
+}
+
+/// Returns how many seconds `username` has to wait before trying to
+/// authenticate from `ip` again, or `None` if they may try now.
+pub fn check(ctx: &Context, ip: Option<IpAddr>, username: &str) -> Result<Option<i64>> {
+    let now = Utc::now().naive_utc();
+    let mut longest = 0;
+    for (kind, key) in keys(ip, username) {
+        if let Some(throttle) = db::read::throttle(&ctx.db_pool, kind, &key)? {
+            longest = cmp::max(longest, wait(&throttle, free_attempts(kind), now));
+        }
+    }
+
+    if longest > 0 {
+        Ok(Some(longest))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Records a failed attempt, locking the account or address once it has
+/// failed too often.
+pub fn failure(ctx: &Context, ip: Option<IpAddr>, username: &str, source: Source) -> Result<()> {
+    let pool = &ctx.db_pool;
+    let now = Utc::now().naive_utc();
+    let ip_name = ip.map(|ip| ip.to_string()).unwrap_or_default();
+    db::create::auth_event(pool, "failed login", username, &ip_name, source.name())?;
+
+    for (kind, key) in keys(ip, username) {
+        let failures = match db::read::throttle(pool, kind, &key)? {
+            Some(ref t) if now - t.last_failure <= Duration::minutes(RESET_MINS) => t.failures + 1,
+            _ => 1,
+        };
+
+        let (limit, event) = if kind == "user" {
+            (ACCOUNT_LOCKOUT, "account locked")
+        } else {
+            (IP_LOCKOUT, "address locked")
+        };
+        let locked_until = if failures % limit == 0 {
+            db::create::auth_event(pool, event, username, &ip_name, source.name())?;
+            Some(now + Duration::minutes(LOCKOUT_MINS))
+        } else {
+            None
+        };
+
+        db::update::throttle(pool, kind, &key, failures, now, locked_until)?;
+    }
+    Ok(())
+}
+
+/// Forgets the failures of an account after it authenticated successfully.
+/// Failures from the address are kept so that one valid account can't be used
+/// to reset the counter while guessing others.
+pub fn success(ctx: &Context, username: &str) -> Result<()> {
+    db::delete::throttle(&ctx.db_pool, "user", &username.to_lowercase())
+}
+
+/// Deletes auth events that are past their retention, this is run
+/// periodically rather than on every attempt.
+pub fn prune_auth_events(pool: &Pool) -> Result<()> {
+    db::delete::auth_events(pool, Utc::now().naive_utc() - Duration::days(AUTH_EVENT_DAYS))
+}
@@ -1,60 +1,111 @@
+pub mod org;
 pub mod repo;
 
-use {db, git};
+use {db, git, oidc, totp, Context, Result};
 use templates::*;
 use types::*;
-use super::{not_found, util};
+use super::{not_found, throttle, util};
 
-use hayaku::Status;
+use hayaku::{Request, Response, Status};
+
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// How long users have to enter their password when linking an OpenID Connect
+/// identity to their account.
+const OIDC_LINK_SECS: u64 = 300;
 
 // GET /signup
 route!{signup, req, res, ctx, {
-    if !ctx.signup {
-        not_found(req, res, ctx)
-    } else if util::check_login(ctx, &req.get_cookies()).is_some() {
+    // Invitations allow signing up even when open signup is disabled
+    let invite = req.form_value("invite").filter(|code| !code.is_empty());
+    if let Some(ref code) = invite {
+        if !db::read::invite_valid(&ctx.db_pool, code)? {
+            redirect!(res, ctx, "", "That invitation is invalid or has expired");
+        }
+    } else if !ctx.signup.load(Ordering::Relaxed) {
+        return not_found(req, res, ctx);
+    }
+
+    if util::current_user(req, ctx, &req.get_cookies()).is_some() {
         redirect!(res, ctx, "", "You already have an account");
-    } else {
-        let navbar = Navbar::new(ctx, None);
-        let body = include_str!("../../../templates/user/signup.html");
-        tmpl!(res, ctx, Some("Signup"), Some(navbar), None, body);
     }
+
+    let domains = if invite.is_some() {
+        String::new()
+    } else {
+        ctx.signup_domains.join(", ")
+    };
+    let navbar = Navbar::new(ctx, None);
+    let body = SignupTmpl { invite: invite, domains: domains };
+    tmpl!(res, ctx, Some("Signup"), Some(navbar), None, body);
 }}
 
 // POST /signup
 route!{signup_post, req, res, ctx, {
-    if !ctx.signup {
+    let invite = req.form_value("invite").filter(|code| !code.is_empty());
+    if invite.is_none() && !ctx.signup.load(Ordering::Relaxed) {
         return not_found(req, res, ctx);
-    } else if util::check_login(ctx, &req.get_cookies()).is_some() {
+    } else if util::current_user(req, ctx, &req.get_cookies()).is_some() {
         redirect!(res, ctx, "", "You already have an account");
     }
 
-    let new_user = NewUser::new(req);
+    let signup_url = match invite {
+        Some(ref code) => format!("signup?invite={}", code),
+        None => String::from("signup"),
+    };
+
+    // The domain allowlist only applies to open signup
+    let domains: &[String] = if invite.is_some() { &[] } else { &ctx.signup_domains };
+    let email = req.form_value("email").unwrap_or_default();
+    if !email_domain_allowed(&email, domains) {
+        redirect!(res, ctx, signup_url, "Signup is limited to email addresses at approved domains");
+    }
+
+    let new_user = NewUser::new(req, domains);
     if new_user.is_none() {
-        redirect!(res, ctx, "signup", "Signup failed");
+        redirect!(res, ctx, signup_url, "Signup failed");
     }
     let new_user = new_user.unwrap();
 
     let pool = &ctx.db_pool;
-    db::create::user(pool, &new_user)?;
+    if db::read::user_exists(pool, &new_user.username)? {
+        redirect!(res, ctx, signup_url, "That username is taken");
+    }
+    if let Some(ref code) = invite {
+        if !db::create::invited_user(pool, &new_user, code)? {
+            redirect!(res, ctx, "", "That invitation is invalid or has expired");
+        }
+    } else {
+        db::create::user(pool, &new_user)?;
+    }
     git::create_user(ctx, &new_user.username)?;
-    util::login(new_user.username, &mut res.cookies(), ctx);
+    let user_id = db::read::user_id(pool, &new_user.username)?;
+    util::login(req, user_id, &mut res.cookies(), ctx)?;
     redirect!(res, ctx, "", "Signup successful");
 }}
 
 // GET /login
 route!{login, req, res, ctx, {
-    if util::check_login(ctx, &req.get_cookies()).is_some() {
+    if util::current_user(req, ctx, &req.get_cookies()).is_some() {
         redirect!(res, ctx, "", "You are already logged in");
+    } else if let Some(user_id) = util::proxy_user(req, ctx)? {
+        // The SSO proxy already authenticated the user
+        util::login(req, user_id, &mut res.cookies(), ctx)?;
+        redirect!(res, ctx, "", "Login successful");
     } else {
         let navbar = Navbar::new(ctx, None);
-        let body = include_str!("../../../templates/user/login.html");
+        let body = LoginTmpl {
+            mount: &ctx.mount,
+            oidc: ctx.oidc.as_ref().map(|p| p.name.as_ref().map_or("single sign-on", |n| n.as_str())),
+        };
         tmpl!(res, ctx, Some("Login"), Some(navbar), None, body);
     }
 }}
 
 // POST /login
 route!{login_post, req, res, ctx, {
-    if util::check_login(ctx, &req.get_cookies()).is_some() {
+    if util::current_user(req, ctx, &req.get_cookies()).is_some() {
         redirect!(res, ctx, "", "You are already logged in");
     }
 
@@ -64,58 +115,304 @@ route!{login_post, req, res, ctx, {
     }
     let login = login.unwrap();
 
+    let ip = util::remote_ip(req);
+    if throttle::check(ctx, ip, &login.username)?.is_some() {
+        redirect!(res, ctx, "login", "Too many failed login attempts, please try again later");
+    }
+
     let pool = &ctx.db_pool;
-    let login_check = db::read::check_login(pool, &login)?;
-    if !login_check {
+    let user_id = if let Some(user_id) = util::check_password(ctx, &login)? {
+        user_id
+    } else {
+        throttle::failure(ctx, ip, &login.username, throttle::Source::Web)?;
         redirect!(res, ctx, "login", "Login failed");
+    };
+    throttle::success(ctx, &login.username)?;
+
+    // Accounts with two-factor authentication need a code before logging in
+    if db::read::totp(pool, user_id)?.is_some() {
+        let username = if let Some(username) = db::read::user_name(pool, user_id)? {
+            username
+        } else {
+            redirect!(res, ctx, "login", "Login failed");
+        };
+        util::begin_pending_login(username, &mut res.cookies(), ctx);
+        redirect!(res, ctx, "login/2fa", "Enter your two-factor authentication code");
     }
 
-    util::login(login.username, &mut res.cookies(), ctx);
+    util::login(req, user_id, &mut res.cookies(), ctx)?;
+    redirect!(res, ctx, "", "Login successful");
+}}
+
+// GET /login/oidc
+route!{login_oidc, req, res, ctx, {
+    let provider = match ctx.oidc {
+        Some(ref provider) => provider,
+        None => return not_found(req, res, ctx),
+    };
+    if util::current_user(req, ctx, &req.get_cookies()).is_some() {
+        redirect!(res, ctx, "", "You are already logged in");
+    }
+
+    let (url, state, pending) = oidc::authorize(provider, &util::oidc_redirect_uri(ctx));
+    util::begin_oidc_login(state, pending, &mut res.cookies(), ctx);
+    ok!(res.redirect(Status::FOUND, &url, "Redirecting to log in"));
+}}
+
+// GET /login/oidc/callback
+route!{login_oidc_callback, req, res, ctx, {
+    let provider = match ctx.oidc {
+        Some(ref provider) => provider,
+        None => return not_found(req, res, ctx),
+    };
+
+    let cookies = req.get_cookies();
+    let state = req.form_value("state").unwrap_or_default();
+    let pending = if let Some(pending) = util::take_oidc_login(ctx, &cookies, &mut res.cookies(), &state) {
+        pending
+    } else {
+        redirect!(res, ctx, "login", "Your login has expired, please try again");
+    };
+    // The provider sends an error instead of a code if the user didn't log in
+    let code = if let Some(code) = req.form_value("code") {
+        code
+    } else {
+        redirect!(res, ctx, "login", "Login failed");
+    };
+    let identity = if let Some(identity) = oidc::exchange(provider, &util::oidc_redirect_uri(ctx), &code, &pending)? {
+        identity
+    } else {
+        redirect!(res, ctx, "login", "Login failed");
+    };
+
+    let pool = &ctx.db_pool;
+    let user_id = if let Some(user_id) = db::read::oidc_user(pool, &provider.issuer, &identity.subject)? {
+        user_id
+    } else {
+        // New identities are matched to accounts by email, so the provider
+        // has to have verified it
+        let email = match identity.email {
+            Some(ref email) if identity.email_verified => email.clone(),
+            _ => {
+                redirect!(res, ctx, "login", "Your account has no verified email address");
+            }
+        };
+
+        if let Some(user_id) = db::read::user_by_email(pool, &email)? {
+            // Local emails are never verified, so anyone could have signed up
+            // with this one. The owner has to prove it with their password.
+            let link = PendingOidcLink {
+                user: user_id,
+                issuer: provider.issuer.clone(),
+                subject: identity.subject.clone(),
+                expires: Instant::now() + Duration::from_secs(OIDC_LINK_SECS),
+            };
+            util::begin_oidc_link(link, &mut res.cookies(), ctx);
+            redirect!(res, ctx, "login/oidc/link", "Enter your password to link your account");
+        }
+
+        if !ctx.signup.load(Ordering::Relaxed) || !email_domain_allowed(&email, &ctx.signup_domains) {
+            redirect!(res, ctx, "login", "No account matches your email address");
+        }
+        let username = identity.username.clone()
+            .unwrap_or_else(|| email.split('@').next().unwrap_or_default().to_string());
+        if db::read::user_exists(pool, &username)? {
+            redirect!(res, ctx, "login", "Your username is already taken");
+        }
+        let new_user = if let Some(new_user) = NewUser::external(username, email) {
+            new_user
+        } else {
+            redirect!(res, ctx, "login", "Your username can't be used here");
+        };
+        db::create::user(pool, &new_user)?;
+        git::create_user(ctx, &new_user.username)?;
+        let user_id = db::read::user_id(pool, &new_user.username)?;
+        db::create::oidc_identity(pool, user_id, &provider.issuer, &identity.subject)?;
+        user_id
+    };
+
+    finish_oidc_login(req, res, ctx, user_id)
+}}
+
+// GET /login/oidc/link
+route!{login_oidc_link, req, res, ctx, {
+    let provider = match ctx.oidc {
+        Some(ref provider) => provider,
+        None => return not_found(req, res, ctx),
+    };
+    let link = if let Some(link) = util::oidc_link(ctx, &req.get_cookies()) {
+        link
+    } else {
+        redirect!(res, ctx, "login", "Your login has expired, please try again");
+    };
+
+    let username = if let Some(username) = db::read::user_name(&ctx.db_pool, link.user)? {
+        username
+    } else {
+        redirect!(res, ctx, "login", "Your login has expired, please try again");
+    };
+
+    let navbar = Navbar::new(ctx, None);
+    let body = OidcLinkTmpl {
+        username: username,
+        provider: provider.name.as_ref().map_or("single sign-on", |n| n.as_str()),
+    };
+    tmpl!(res, ctx, Some("Link Account"), Some(navbar), None, body);
+}}
+
+// POST /login/oidc/link
+route!{login_oidc_link_post, req, res, ctx, {
+    if ctx.oidc.is_none() {
+        return not_found(req, res, ctx);
+    }
+    let cookies = req.get_cookies();
+    let link = if let Some(link) = util::oidc_link(ctx, &cookies) {
+        link
+    } else {
+        redirect!(res, ctx, "login", "Your login has expired, please try again");
+    };
+
+    let pool = &ctx.db_pool;
+    let username = if let Some(username) = db::read::user_name(pool, link.user)? {
+        username
+    } else {
+        redirect!(res, ctx, "login", "Your login has expired, please try again");
+    };
+    let ip = util::remote_ip(req);
+    if throttle::check(ctx, ip, &username)?.is_some() {
+        redirect!(res, ctx, "login/oidc/link", "Too many failed login attempts, please try again later");
+    }
+
+    let login = Login {
+        username: username.clone(),
+        password: req.form_value("password").unwrap_or_default(),
+    };
+    if util::check_password(ctx, &login)? != Some(link.user) {
+        throttle::failure(ctx, ip, &username, throttle::Source::Web)?;
+        redirect!(res, ctx, "login/oidc/link", "Wrong password");
+    }
+    throttle::success(ctx, &username)?;
+
+    util::finish_oidc_link(&cookies, &mut res.cookies(), ctx);
+    db::create::oidc_identity(pool, link.user, &link.issuer, &link.subject)?;
+    finish_oidc_login(req, res, ctx, link.user)
+}}
+
+/// Logs in `user` after they came back from the OpenID Connect provider,
+/// asking for their second factor first if they have one.
+fn finish_oidc_login(req: &mut Request, res: &mut Response, ctx: &Context, user: i32) -> Result<()> {
+    let pool = &ctx.db_pool;
+    let username = match (db::read::user_name(pool, user)?, db::read::user_disabled(pool, user)?) {
+        (Some(username), Some(false)) => username,
+        _ => {
+            redirect!(res, ctx, "login", "Login failed");
+        }
+    };
+    let ip = util::remote_ip(req).map(|ip| ip.to_string()).unwrap_or_default();
+    db::create::auth_event(pool, "oidc login", &username, &ip, "oidc")?;
+
+    if db::read::totp(pool, user)?.is_some() {
+        util::begin_pending_login(username, &mut res.cookies(), ctx);
+        redirect!(res, ctx, "login/2fa", "Enter your two-factor authentication code");
+    }
+
+    util::login(req, user, &mut res.cookies(), ctx)?;
+    redirect!(res, ctx, "", "Login successful");
+}
+
+// GET /login/2fa
+route!{login_2fa, req, res, ctx, {
+    if util::pending_login(ctx, &req.get_cookies()).is_none() {
+        redirect!(res, ctx, "login", "Your login has expired, please log in again");
+    }
+
+    let navbar = Navbar::new(ctx, None);
+    let body = include_str!("../../../templates/user/login_2fa.html");
+    tmpl!(res, ctx, Some("Two-Factor Authentication"), Some(navbar), None, body);
+}}
+
+// POST /login/2fa
+route!{login_2fa_post, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let username = if let Some(username) = util::pending_login(ctx, &cookies) {
+        username
+    } else {
+        redirect!(res, ctx, "login", "Your login has expired, please log in again");
+    };
+
+    let ip = util::remote_ip(req);
+    if throttle::check(ctx, ip, &username)?.is_some() {
+        redirect!(res, ctx, "login/2fa", "Too many failed login attempts, please try again later");
+    }
+
+    let pool = &ctx.db_pool;
+    let user_id = db::read::user_id(pool, &username)?;
+    let code = req.form_value("code").unwrap_or_default();
+    let valid = match db::read::totp(pool, user_id)? {
+        Some((secret, last_step)) => {
+            if let Some(step) = totp::verify(&secret, &code, last_step) {
+                db::update::totp_last_step(pool, user_id, step)?;
+                true
+            } else {
+                db::delete::recovery_code(pool, user_id, &totp::hash_recovery_code(&code))?
+            }
+        }
+        // Two-factor authentication was turned off in the meantime
+        None => true,
+    };
+    if !valid {
+        throttle::failure(ctx, ip, &username, throttle::Source::Web)?;
+        redirect!(res, ctx, "login/2fa", "Invalid code");
+    }
+    throttle::success(ctx, &username)?;
+
+    util::finish_pending_login(&cookies, &mut res.cookies(), ctx);
+    util::login(req, user_id, &mut res.cookies(), ctx)?;
     redirect!(res, ctx, "", "Login successful");
 }}
 
 // GET /logout
 route!{logout, req, res, ctx, {
-    /*
-    let cookies = req.get_cookies();
-    if let Some(cookie) = cookies.get("session_key") {
-        let cookies = res.cookies();
-        ctx.logins.lock().unwrap().remove(cookie.value());
-        let del_cookie = Cookie::build("session_key", "")
-            .max_age(Duration::seconds(0))
-            .expires(time::empty_tm())
-            .finish();
-        cookies.add(del_cookie);
-
-        let del_cookie = Cookie::build("dotcom_user", "")
-            .max_age(Duration::seconds(0))
-            .expires(time::empty_tm())
-            .finish();
-        cookies.add(del_cookie);
-    }
-    */
-    util::logout(&req.get_cookies(), &mut res.cookies(), ctx);
+    let cookies = req.get_cookies();
+    let current = if let Some(user) = util::current_user(req, ctx, &cookies) {
+        user
+    } else {
+        redirect!(res, ctx, "", "You are not logged in");
+    };
+
+    // Logging out is done with a form so that other sites can't log users out
+    let navbar = Navbar::new(ctx, Some(&current));
+    let body = LogoutTmpl { mount: &ctx.mount, csrf: util::csrf_token(&cookies) };
+    tmpl!(res, ctx, Some("Logout"), Some(navbar), None, body);
+}}
+
+// POST /logout
+route!{logout_post, req, res, ctx, {
+    let cookies = req.get_cookies();
+    check_csrf!(req, &cookies, res, ctx);
+    util::logout(&cookies, &mut res.cookies(), ctx);
     redirect!(res, ctx, "", "Logout successful");
 }}
 
 // GET /settings
 route!{settings, req, res, ctx, {
     let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let current = check_login!(req, &cookies, res, ctx);
 
-    let mut settings = db::read::settings(&ctx.db_pool, username, ctx)?;
-    let navbar = Navbar::new(ctx, Some(username));
+    let mut settings = db::read::settings(&ctx.db_pool, current.id, &current.username, ctx)?;
+    settings.csrf = util::csrf_token(&cookies);
+    let navbar = Navbar::new(ctx, Some(&current));
     tmpl!(res, ctx, Some("Settings"), Some(navbar), None, settings);
 }}
 
 // POST /settings/add-ssh-key
 route!{add_ssh_key, req, res, ctx, {
     let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
 
     let pool = &ctx.db_pool;
-    let user_id = db::read::user_id(pool, username)?;
-    let ssh_key = if let Some(key) = NewSshKey::new(req, user_id) {
+    let ssh_key = if let Some(key) = NewSshKey::new(req, current.id) {
         key
     } else {
         redirect!(res, ctx, "settings", "Invalid data");
@@ -127,14 +424,15 @@ route!{add_ssh_key, req, res, ctx, {
     redirect!(res, ctx, "settings", "SSH key added");
 }}
 
-// GET /settings/delete-ssh-key/{id}
+// POST /settings/delete-ssh-key/{id}
 route!{delete_ssh_key, req, res, ctx, {
     let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
     let id = parse_param!(req, res, ctx, "id", i32);
 
     let pool = &ctx.db_pool;
-    if db::read::user_owns_key(pool, username, id)? {
+    if db::read::user_owns_key(pool, current.id, id)? {
         git::delete_ssh_key(ctx, id)?;
         db::delete::public_key(pool, id)?;
         redirect!(res, ctx, "settings", "Key deleted");
@@ -142,3 +440,309 @@ route!{delete_ssh_key, req, res, ctx, {
         redirect!(res, ctx, "settings", "Key does not exist");
     }
 }}
+
+// POST /settings/add-signing-key
+route!{add_signing_key, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let key = if let Some(key) = NewSigningKey::new(req, current.id) {
+        key
+    } else {
+        redirect!(res, ctx, "settings", "Invalid signing key");
+    };
+    db::create::signing_key(pool, &key)?;
+
+    redirect!(res, ctx, "settings", "Signing key added");
+}}
+
+// POST /settings/delete-signing-key/{id}
+route!{delete_signing_key, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i32);
+
+    let pool = &ctx.db_pool;
+    if db::read::user_owns_signing_key(pool, current.id, id)? {
+        db::delete::signing_key(pool, id)?;
+        redirect!(res, ctx, "settings", "Key deleted");
+    } else {
+        redirect!(res, ctx, "settings", "Key does not exist");
+    }
+}}
+
+// POST /settings/invites/new
+route!{new_invite, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let invite = if let Some(invite) = NewInvite::new(req, current.id) {
+        invite
+    } else {
+        redirect!(res, ctx, "settings", "Invalid data");
+    };
+    db::create::invite(pool, &invite)?;
+
+    redirect!(res, ctx, "settings", "Invitation created");
+}}
+
+// POST /settings/invites/{id}/delete
+route!{delete_invite, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let id = parse_param!(req, res, ctx, "id", i32);
+
+    let pool = &ctx.db_pool;
+    if db::read::invite_creator(pool, id)? == Some(current.id) {
+        db::delete::invite(pool, id)?;
+        redirect!(res, ctx, "settings", "Invitation revoked");
+    } else {
+        redirect!(res, ctx, "settings", "Invitation does not exist");
+    }
+}}
+
+// GET /settings/2fa
+route!{totp_setup, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+
+    if db::read::totp(&ctx.db_pool, current.id)?.is_some() {
+        redirect!(res, ctx, "settings", "Two-factor authentication is already enabled");
+    }
+
+    let navbar = Navbar::new(ctx, Some(&current));
+    let body = TotpSetupTmpl {
+        mount: &ctx.mount,
+        issuer: &ctx.name,
+        username: &current.username,
+        secret: util::begin_totp_setup(ctx, current.id),
+        csrf: util::csrf_token(&cookies),
+    };
+    tmpl!(res, ctx, Some("Two-Factor Authentication"), Some(navbar), None, body);
+}}
+
+// POST /settings/2fa/enable
+route!{totp_enable, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let code = if let Some(c) = req.form_value("code") {
+        c
+    } else {
+        redirect!(res, ctx, "settings/2fa", "Invalid data");
+    };
+    let secret = if let Some(s) = util::pending_totp(ctx, current.id) {
+        s
+    } else {
+        redirect!(res, ctx, "settings/2fa", "The setup expired, please scan the new secret and try again");
+    };
+
+    // The code proves that the authenticator app was set up correctly
+    let step = if let Some(step) = totp::verify(&secret, &code, None) {
+        step
+    } else {
+        redirect!(res, ctx, "settings/2fa", "Invalid code, please scan the new secret and try again");
+    };
+
+    let pool = &ctx.db_pool;
+    db::update::totp(pool, current.id, Some(&secret), Some(step))?;
+    util::finish_totp_setup(ctx, current.id);
+    let codes = totp::generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+    db::create::recovery_codes(pool, current.id, &hashes)?;
+
+    let navbar = Navbar::new(ctx, Some(&current));
+    let body = RecoveryCodesTmpl { mount: &ctx.mount, codes: codes };
+    tmpl!(res, ctx, Some("Recovery Codes"), Some(navbar), None, body);
+}}
+
+// POST /settings/2fa/disable
+route!{totp_disable, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let login = Login {
+        username: current.username.clone(),
+        password: req.form_value("password").unwrap_or_default(),
+    };
+    let pool = &ctx.db_pool;
+    if util::check_password(ctx, &login)? != Some(current.id) {
+        redirect!(res, ctx, "settings", "Incorrect password");
+    }
+
+    db::update::totp(pool, current.id, None, None)?;
+    db::delete::recovery_codes(pool, current.id)?;
+    redirect!(res, ctx, "settings", "Two-factor authentication disabled");
+}}
+
+// POST /settings/2fa/recovery-codes
+route!{recovery_codes, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let login = Login {
+        username: current.username.clone(),
+        password: req.form_value("password").unwrap_or_default(),
+    };
+    let pool = &ctx.db_pool;
+    if util::check_password(ctx, &login)? != Some(current.id) {
+        redirect!(res, ctx, "settings", "Incorrect password");
+    }
+
+    if db::read::totp(pool, current.id)?.is_none() {
+        redirect!(res, ctx, "settings", "Two-factor authentication is not enabled");
+    }
+    let codes = totp::generate_recovery_codes();
+    let hashes: Vec<String> = codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+    db::create::recovery_codes(pool, current.id, &hashes)?;
+
+    let navbar = Navbar::new(ctx, Some(&current));
+    let body = RecoveryCodesTmpl { mount: &ctx.mount, codes: codes };
+    tmpl!(res, ctx, Some("Recovery Codes"), Some(navbar), None, body);
+}}
+
+// POST /settings/password
+route!{change_password, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    if ctx.ldap.is_some() && db::read::is_ldap_user(&ctx.db_pool, &current.username)? {
+        redirect!(res, ctx, "settings", "Your password is managed by your organization's directory");
+    }
+    let change = if let Some(change) = PasswordChange::new(req) {
+        change
+    } else {
+        redirect!(res, ctx, "settings", "The new passwords do not match");
+    };
+
+    let login = Login {
+        username: current.username.clone(),
+        password: change.old_password,
+    };
+    let pool = &ctx.db_pool;
+    if util::check_password(ctx, &login)? != Some(current.id) {
+        redirect!(res, ctx, "settings", "Incorrect password");
+    }
+
+    db::update::password(pool, current.id, &change.password)?;
+    // Sign out everywhere else in case the old password was compromised
+    db::delete::sessions(pool, current.id, Some(&util::current_session(&cookies)))?;
+    redirect!(res, ctx, "settings", "Password changed, all other sessions have been signed out");
+}}
+
+// POST /settings/email
+route!{change_email, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let email = req.form_value("email").unwrap_or_default();
+    let email = email.trim();
+    if !email.contains('@') {
+        redirect!(res, ctx, "settings", "Invalid email address");
+    }
+    // Users must stay within the domains allowed to sign up
+    if !email_domain_allowed(email, &ctx.signup_domains) {
+        redirect!(res, ctx, "settings", "Email addresses from that domain are not allowed");
+    }
+
+    let pool = &ctx.db_pool;
+    db::update::email(pool, current.id, email)?;
+    redirect!(res, ctx, "settings", "Email changed");
+}}
+
+// POST /settings/delete
+route!{delete_account, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    if req.form_value("confirm").as_ref().map(|s| s.as_str()) != Some(current.username.as_str()) {
+        redirect!(res, ctx, "settings", "You must enter your username to delete your account");
+    }
+    let login = Login {
+        username: current.username.clone(),
+        password: req.form_value("password").unwrap_or_default(),
+    };
+    let pool = &ctx.db_pool;
+    if util::check_password(ctx, &login)? != Some(current.id) {
+        redirect!(res, ctx, "settings", "Incorrect password");
+    }
+
+    // Organizations must not be left without an owner
+    if util::sole_owned_org(pool, current.id)?.is_some() {
+        redirect!(res, ctx, "settings",
+                  "You are the only owner of an organization, add another owner or delete it first");
+    }
+
+    git::delete_ssh_keys(ctx, &db::read::public_key_ids(pool, current.id)?)?;
+    db::delete::user(pool, &current.username)?;
+    git::delete_user(ctx, &current.username)?;
+    util::logout(&cookies, &mut res.cookies(), ctx);
+    redirect!(res, ctx, "", "Your account has been deleted");
+}}
+
+// GET /settings/sessions
+route!{sessions, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let current_session = util::current_session(&cookies);
+    let mut sessions = db::read::sessions(pool, current.id)?;
+    for session in &mut sessions {
+        session.current = session.id == current_session;
+    }
+
+    let navbar = Navbar::new(ctx, Some(&current));
+    let body = SessionsTmpl {
+        mount: &ctx.mount,
+        sessions: sessions,
+        csrf: util::csrf_token(&cookies),
+    };
+    tmpl!(res, ctx, Some("Sessions"), Some(navbar), None, body);
+}}
+
+// POST /settings/sessions/revoke
+route!{revoke_session, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let id = req.form_value("id").unwrap_or_default();
+    if id == util::current_session(&cookies) {
+        util::logout(&cookies, &mut res.cookies(), ctx);
+        redirect!(res, ctx, "", "Logout successful");
+    }
+
+    // Only sessions of the logged in user can be revoked
+    let pool = &ctx.db_pool;
+    if db::read::sessions(pool, current.id)?.iter().any(|s| s.id == id) {
+        db::delete::session(pool, &id)?;
+        redirect!(res, ctx, "settings/sessions", "Session revoked");
+    } else {
+        redirect!(res, ctx, "settings/sessions", "Session does not exist");
+    }
+}}
+
+// POST /settings/sessions/revoke-all
+route!{revoke_all_sessions, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let pool = &ctx.db_pool;
+    db::delete::sessions(pool, current.id, None)?;
+    util::logout(&cookies, &mut res.cookies(), ctx);
+    redirect!(res, ctx, "", "You have been signed out everywhere");
+}}
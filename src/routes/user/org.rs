@@ -0,0 +1,295 @@
+use {db, git};
+use templates::*;
+use types::*;
+use super::{not_found, util};
+
+use hayaku::Status;
+
+macro_rules! check_org_owner {
+    ( $current:expr, $org:expr, $req:ident, $res:ident, $ctx:ident ) => {
+        {
+            let pool = &$ctx.db_pool;
+            if !db::read::is_org(pool, &$org)? {
+                return not_found($req, $res, $ctx);
+            }
+            let org_id = db::read::user_id(pool, &$org)?;
+            if db::read::org_role(pool, org_id, $current.id)? != Some(true) {
+                redirect!($res, $ctx, $org, "You must be an owner of this organization");
+            }
+            org_id
+        }
+    };
+}
+
+macro_rules! read_team {
+    ( $org_id:expr, $req:ident, $res:ident, $ctx:ident ) => {
+        {
+            let name = $req.get_param("team");
+            if let Some(team) = db::read::team(&$ctx.db_pool, $org_id, &name)? {
+                team
+            } else {
+                return not_found($req, $res, $ctx);
+            }
+        }
+    };
+}
+
+// GET /org/new
+route!{new, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+
+    let navbar = Navbar::new(ctx, Some(&current));
+    let body = OrgNewTmpl { csrf: util::csrf_token(&cookies) };
+    tmpl!(res, ctx, Some("Create a New Organization"), Some(navbar), None, body);
+}}
+
+// POST /org/new
+route!{new_post, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let org = if let Some(org) = NewOrg::new(req) {
+        org
+    } else {
+        redirect!(res, ctx, "org/new", "Invalid input");
+    };
+
+    let pool = &ctx.db_pool;
+    if db::read::user_exists(pool, &org.name)? {
+        redirect!(res, ctx, "org/new", "That name is already taken");
+    }
+
+    db::create::org(pool, &org, current.id)?;
+    git::create_user(ctx, &org.name)?;
+    redirect!(res, ctx, org.name, "Organization created");
+}}
+
+// GET /org/{org}/settings
+route!{settings, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    let org = req.get_param("org");
+    let org_id = check_org_owner!(current, org, req, res, ctx);
+
+    let members = db::read::org_members(&ctx.db_pool, org_id)?;
+    let teams = db::read::teams(&ctx.db_pool, org_id)?;
+    let navbar = Navbar::new(ctx, Some(&current));
+    let body = OrgSettingsTmpl {
+        mount: &ctx.mount,
+        org: &org,
+        members: members,
+        teams: teams,
+        csrf: util::csrf_token(&cookies),
+    };
+    tmpl!(res, ctx, Some(&org), Some(navbar), None, body);
+}}
+
+// POST /org/{org}/members/add
+route!{add_member, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let org = req.get_param("org");
+    let org_id = check_org_owner!(current, org, req, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let member = if let Some(member) = req.form_value("username") {
+        member
+    } else {
+        redirect!(res, ctx, format!("org/{}/settings", org), "Invalid data");
+    };
+    if !db::read::user_exists(pool, &member)? || db::read::is_org(pool, &member)? {
+        redirect!(res, ctx, format!("org/{}/settings", org), "User does not exist");
+    }
+
+    let is_owner = req.form_value("owner") == Some(String::from("on"));
+    let member_id = db::read::user_id(pool, &member)?;
+    db::create::org_member(pool, org_id, member_id, is_owner)?;
+    redirect!(res, ctx, format!("org/{}/settings", org), "Member added");
+}}
+
+// POST /org/{org}/members/remove
+route!{remove_member, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let org = req.get_param("org");
+    let org_id = check_org_owner!(current, org, req, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let member = if let Some(member) = req.form_value("username") {
+        member
+    } else {
+        redirect!(res, ctx, format!("org/{}/settings", org), "Invalid data");
+    };
+    if !db::read::user_exists(pool, &member)? {
+        redirect!(res, ctx, format!("org/{}/settings", org), "User does not exist");
+    }
+
+    let members = db::read::org_members(pool, org_id)?;
+    let owners = members.iter().filter(|m| m.is_owner).count();
+    if owners == 1 && members.iter().any(|m| m.is_owner && m.username == member) {
+        redirect!(res, ctx, format!("org/{}/settings", org),
+                  "An organization must have at least one owner");
+    }
+
+    let member_id = db::read::user_id(pool, &member)?;
+    db::delete::org_member(pool, org_id, member_id)?;
+    redirect!(res, ctx, format!("org/{}/settings", org), "Member removed");
+}}
+
+// POST /org/{org}/teams/new
+route!{new_team, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let org = req.get_param("org");
+    let org_id = check_org_owner!(current, org, req, res, ctx);
+
+    let team = if let Some(team) = NewTeam::new(req, org_id) {
+        team
+    } else {
+        redirect!(res, ctx, format!("org/{}/settings", org), "Invalid data");
+    };
+
+    let pool = &ctx.db_pool;
+    if db::read::team(pool, org_id, &team.name)?.is_some() {
+        redirect!(res, ctx, format!("org/{}/settings", org), "That team already exists");
+    }
+    db::create::team(pool, &team)?;
+    redirect!(res, ctx, format!("org/{}/teams/{}", org, team.name), "Team created");
+}}
+
+// GET /org/{org}/teams/{team}
+route!{team, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    let org = req.get_param("org");
+    let org_id = check_org_owner!(current, org, req, res, ctx);
+    let team = read_team!(org_id, req, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let members = db::read::team_members(pool, team.id)?;
+    let repos = db::read::team_repos(pool, team.id)?;
+    let navbar = Navbar::new(ctx, Some(&current));
+    let body = TeamTmpl {
+        mount: &ctx.mount,
+        org: &org,
+        team: team,
+        members: members,
+        repos: repos,
+        csrf: util::csrf_token(&cookies),
+    };
+    tmpl!(res, ctx, Some(&org), Some(navbar), None, body);
+}}
+
+// POST /org/{org}/teams/{team}/delete
+route!{delete_team, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let org = req.get_param("org");
+    let org_id = check_org_owner!(current, org, req, res, ctx);
+    let team = read_team!(org_id, req, res, ctx);
+
+    db::delete::team(&ctx.db_pool, team.id)?;
+    redirect!(res, ctx, format!("org/{}/settings", org), "Team deleted");
+}}
+
+// POST /org/{org}/teams/{team}/members/add
+route!{add_team_member, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let org = req.get_param("org");
+    let org_id = check_org_owner!(current, org, req, res, ctx);
+    let team = read_team!(org_id, req, res, ctx);
+    let team_url = format!("org/{}/teams/{}", org, team.name);
+
+    let pool = &ctx.db_pool;
+    let member = if let Some(member) = req.form_value("username") {
+        member
+    } else {
+        redirect!(res, ctx, team_url, "Invalid data");
+    };
+    if !db::read::user_exists(pool, &member)? {
+        redirect!(res, ctx, team_url, "User does not exist");
+    }
+
+    // Team members must belong to the organization
+    let member_id = db::read::user_id(pool, &member)?;
+    if db::read::org_role(pool, org_id, member_id)?.is_none() {
+        redirect!(res, ctx, team_url, "User is not a member of this organization");
+    }
+    db::create::team_member(pool, team.id, member_id)?;
+    redirect!(res, ctx, team_url, "Member added");
+}}
+
+// POST /org/{org}/teams/{team}/members/remove
+route!{remove_team_member, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let org = req.get_param("org");
+    let org_id = check_org_owner!(current, org, req, res, ctx);
+    let team = read_team!(org_id, req, res, ctx);
+    let team_url = format!("org/{}/teams/{}", org, team.name);
+
+    let pool = &ctx.db_pool;
+    let member = if let Some(member) = req.form_value("username") {
+        member
+    } else {
+        redirect!(res, ctx, team_url, "Invalid data");
+    };
+    if !db::read::user_exists(pool, &member)? {
+        redirect!(res, ctx, team_url, "User does not exist");
+    }
+
+    let member_id = db::read::user_id(pool, &member)?;
+    db::delete::team_member(pool, team.id, member_id)?;
+    redirect!(res, ctx, team_url, "Member removed");
+}}
+
+// POST /org/{org}/teams/{team}/repos/add
+route!{add_team_repo, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let org = req.get_param("org");
+    let org_id = check_org_owner!(current, org, req, res, ctx);
+    let team = read_team!(org_id, req, res, ctx);
+    let team_url = format!("org/{}/teams/{}", org, team.name);
+
+    let reponame = req.form_value("repo").unwrap_or_default();
+    let repo = if let Some(repo) = db::read::repo_id(&ctx.db_pool, &org, &reponame)? {
+        repo
+    } else {
+        redirect!(res, ctx, team_url, "Repo does not exist");
+    };
+
+    db::create::team_repo(&ctx.db_pool, team.id, repo)?;
+    redirect!(res, ctx, team_url, "Repo added");
+}}
+
+// POST /org/{org}/teams/{team}/repos/remove
+route!{remove_team_repo, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let org = req.get_param("org");
+    let org_id = check_org_owner!(current, org, req, res, ctx);
+    let team = read_team!(org_id, req, res, ctx);
+    let team_url = format!("org/{}/teams/{}", org, team.name);
+
+    let reponame = req.form_value("repo").unwrap_or_default();
+    let repo = if let Some(repo) = db::read::repo_id(&ctx.db_pool, &org, &reponame)? {
+        repo
+    } else {
+        redirect!(res, ctx, team_url, "Repo does not exist");
+    };
+
+    db::delete::team_repo(&ctx.db_pool, team.id, repo)?;
+    redirect!(res, ctx, team_url, "Repo removed");
+}}
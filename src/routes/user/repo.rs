@@ -5,80 +5,133 @@ use super::{not_found, util};
 
 use hayaku::Status;
 
+macro_rules! check_repo_admin {
+    ( $current:expr, $user:expr, $reponame:expr, $res:ident, $ctx:ident ) => {
+        {
+            let pool = &$ctx.db_pool;
+            if db::read::repo_access(pool, $current.id, &$user, &$reponame)? < Access::Admin {
+                redirect!($res, $ctx, format!("{}/{}", $user, $reponame),
+                          "You must be an admin of a repo to change its settings");
+            }
+        }
+    };
+}
+
+/// Loads a pending transfer that `$current` may accept, either to themselves
+/// or to an organization they own.
+macro_rules! read_transfer {
+    ( $current:expr, $req:ident, $res:ident, $ctx:ident ) => {
+        {
+            let pool = &$ctx.db_pool;
+            let id = parse_param!($req, $res, $ctx, "id", i32);
+            let transfer = if let Some(transfer) = db::read::repo_transfer(pool, id)? {
+                transfer
+            } else {
+                redirect!($res, $ctx, "settings", "Transfer does not exist");
+            };
+
+            if transfer.new_owner_id != $current.id &&
+               db::read::org_role(pool, transfer.new_owner_id, $current.id)? != Some(true)
+            {
+                redirect!($res, $ctx, "settings", "Transfer does not exist");
+            }
+            transfer
+        }
+    };
+}
+
 // GET /repo/new
 route!{new, req, res, ctx, {
     let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let current = check_login!(req, &cookies, res, ctx);
 
-    let navbar = Navbar::new(ctx, Some(username));
-    let body = include_str!("../../../templates/user/repo_new.html");
+    let orgs = db::read::owned_orgs(&ctx.db_pool, current.id)?;
+    let navbar = Navbar::new(ctx, Some(&current));
+    let body = RepoNewTmpl { username: &current.username, orgs: orgs, csrf: util::csrf_token(&cookies) };
     tmpl!(res, ctx, Some("Create a New Repository"), Some(navbar), None, body);
 }}
 
 // POST /repo/new
 route!{new_post, req, res, ctx, {
     let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
 
     let pool = &ctx.db_pool;
-    let user_id = db::read::user_id(pool, username)?;
-    let repo = if let Some(repo) = Repo::new(req, user_id) {
+    let owner = req.form_value("owner").unwrap_or_else(|| current.username.clone());
+    if !db::read::user_exists(pool, &owner)? {
+        redirect!(res, ctx, "repo/new", "Invalid input");
+    }
+    let owner_id = db::read::user_id(pool, &owner)?;
+    // Repos can be created for yourself or for any organization you own
+    if owner_id != current.id && db::read::org_role(pool, owner_id, current.id)? != Some(true) {
+        redirect!(res, ctx, "repo/new", "You can't create repositories for that owner");
+    }
+
+    let repo = if let Some(repo) = Repo::new(req, owner_id) {
         repo
     } else {
         redirect!(res, ctx, "repo/new", "Invalid input");
     };
 
-    if db::read::repo_exists(pool, username, &repo.name)? {
+    if db::read::repo_exists(pool, &owner, &repo.name)? {
         redirect!(res, ctx, "repo/new", "That repo already exists");
     }
     db::create::repo(pool, &repo)?;
 
-    git::init(ctx, username, &repo.name)?;
+    git::init(ctx, &owner, &repo.name)?;
 
-    redirect!(res, ctx, format!("{}/{}", username, repo.name), "Repo created");
+    redirect!(res, ctx, format!("{}/{}", owner, repo.name), "Repo created");
 }}
 
 // GET /{user}/{repo}/settings
 route!{settings, req, res, ctx, {
     let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let current = check_login!(req, &cookies, res, ctx);
 
     let user = req.get_param("user");
     let reponame = req.get_param("repo");
 
-    // TODO: we probably want a different check here
-    if username != user {
-        redirect!(res, ctx, format!("{}/{}", user, reponame), "You must own a repo to delete it");
-    }
-
+    check_repo_admin!(current, user, reponame, res, ctx);
 
     let pool = &ctx.db_pool;
-    let repo = if let Some(repo) = db::read::repo(pool, username, &reponame)? {
+    let repo = if let Some(repo) = db::read::repo(pool, &user, &reponame)? {
         repo
     } else {
         return not_found(req, res, ctx);
     };
 
-    let navbar = Navbar::new(ctx, Some(username));
-    let body = RepoSettingsTmpl { mount: &ctx.mount, username: username, repo: repo };
-    tmpl!(res, ctx, Some(username), Some(navbar), None, body);
+    let repo_id = db::read::repo_id(pool, &user, &reponame)?.unwrap();
+    let collaborators = db::read::collaborators(pool, repo_id)?;
+    let branch_rules = db::read::branch_rules(pool, repo_id)?;
+    let transfer = db::read::outgoing_transfer(pool, repo_id)?;
+
+    let navbar = Navbar::new(ctx, Some(&current));
+    let body = RepoSettingsTmpl {
+        mount: &ctx.mount,
+        username: &user,
+        repo: repo,
+        collaborators: collaborators,
+        branch_rules: branch_rules,
+        transfer: transfer,
+        csrf: util::csrf_token(&cookies),
+    };
+    tmpl!(res, ctx, Some(&current.username), Some(navbar), None, body);
 }}
 
 // POST /{user}/{repo}/settings/name
 route!{settings_name, req, res, ctx, {
     let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
 
     let user = req.get_param("user");
     let reponame = req.get_param("repo");
 
-    // TODO: we probably want a different check here
-    if username != user {
-        redirect!(res, ctx, format!("{}/{}", user, reponame), "You must own a repo to delete it");
-    }
+    check_repo_admin!(current, user, reponame, res, ctx);
 
     let pool = &ctx.db_pool;
-    if !db::read::repo_exists(pool, username, &reponame)? {
+    if !db::read::repo_exists(pool, &user, &reponame)? {
         redirect!(res, ctx, format!("{}/{}", user, reponame), "Repo does not exist");
     }
 
@@ -88,23 +141,228 @@ route!{settings_name, req, res, ctx, {
         redirect!(res, ctx, format!("{}/{}", user, reponame), "Invalid  data");
     };
 
-    db::update::repo_name(pool, username, &reponame, &new_name)?;
-    git::mov(ctx, username, &reponame, &new_name)?;
-    redirect!(res, ctx, format!("{}/{}", username, new_name), "Repo name changed");
+    db::update::repo_name(pool, &user, &reponame, &new_name)?;
+    git::mov(ctx, &user, &reponame, &new_name)?;
+    redirect!(res, ctx, format!("{}/{}", user, new_name), "Repo name changed");
+}}
+
+// POST /{user}/{repo}/settings/collaborators/add
+route!{add_collaborator, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let user = req.get_param("user");
+    let reponame = req.get_param("repo");
+    let settings_url = format!("{}/{}/settings", user, reponame);
+
+    check_repo_admin!(current, user, reponame, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let repo = if let Some(repo) = db::read::repo_id(pool, &user, &reponame)? {
+        repo
+    } else {
+        return not_found(req, res, ctx);
+    };
+
+    let (member, access) = if let (Some(m), Some(a)) = (req.form_value("username"), req.form_value("access")) {
+        (m, a)
+    } else {
+        redirect!(res, ctx, settings_url, "Invalid data");
+    };
+    let access = if let Some(access) = Access::new(&access) {
+        access
+    } else {
+        redirect!(res, ctx, settings_url, "Invalid data");
+    };
+    if !db::read::user_exists(pool, &member)? || db::read::is_org(pool, &member)? {
+        redirect!(res, ctx, settings_url, "User does not exist");
+    }
+
+    let member_id = db::read::user_id(pool, &member)?;
+    db::create::collaborator(pool, repo, member_id, access)?;
+    redirect!(res, ctx, settings_url, "Collaborator added");
+}}
+
+// POST /{user}/{repo}/settings/collaborators/remove
+route!{remove_collaborator, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let user = req.get_param("user");
+    let reponame = req.get_param("repo");
+    let settings_url = format!("{}/{}/settings", user, reponame);
+
+    check_repo_admin!(current, user, reponame, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let repo = if let Some(repo) = db::read::repo_id(pool, &user, &reponame)? {
+        repo
+    } else {
+        return not_found(req, res, ctx);
+    };
+
+    let member = req.form_value("username").unwrap_or_default();
+    if !db::read::user_exists(pool, &member)? {
+        redirect!(res, ctx, settings_url, "User does not exist");
+    }
+
+    let member_id = db::read::user_id(pool, &member)?;
+    db::delete::collaborator(pool, repo, member_id)?;
+    redirect!(res, ctx, settings_url, "Collaborator removed");
+}}
+
+// POST /{user}/{repo}/settings/branches/add
+route!{add_branch_rule, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let user = req.get_param("user");
+    let reponame = req.get_param("repo");
+    let settings_url = format!("{}/{}/settings", user, reponame);
+
+    check_repo_admin!(current, user, reponame, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let repo = if let Some(repo) = db::read::repo_id(pool, &user, &reponame)? {
+        repo
+    } else {
+        return not_found(req, res, ctx);
+    };
+
+    let rule = if let Some(rule) = NewBranchRule::new(req) {
+        rule
+    } else {
+        redirect!(res, ctx, settings_url, "Invalid data");
+    };
+
+    let mut users = Vec::new();
+    for name in &rule.users {
+        if !db::read::user_exists(pool, name)? || db::read::is_org(pool, name)? {
+            redirect!(res, ctx, settings_url, "A listed user does not exist");
+        }
+        users.push(db::read::user_id(pool, name)?);
+    }
+
+    // Only repos owned by an organization have teams
+    let mut teams = Vec::new();
+    let owner = db::read::user_id(pool, &user)?;
+    for name in &rule.teams {
+        if let Some(team) = db::read::team(pool, owner, name)? {
+            teams.push(team.id);
+        } else {
+            redirect!(res, ctx, settings_url, "A listed team does not exist");
+        }
+    }
+
+    if db::read::branch_rules(pool, repo)?.iter().any(|r| r.pattern == rule.pattern) {
+        redirect!(res, ctx, settings_url, "A rule for that pattern already exists");
+    }
+    db::create::branch_rule(pool, repo, &rule, &users, &teams)?;
+    redirect!(res, ctx, settings_url, "Branch protection added");
+}}
+
+// POST /{user}/{repo}/settings/branches/delete
+route!{delete_branch_rule, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let user = req.get_param("user");
+    let reponame = req.get_param("repo");
+    let settings_url = format!("{}/{}/settings", user, reponame);
+
+    check_repo_admin!(current, user, reponame, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let repo = if let Some(repo) = db::read::repo_id(pool, &user, &reponame)? {
+        repo
+    } else {
+        return not_found(req, res, ctx);
+    };
+
+    let id = if let Some(id) = req.form_value("id").and_then(|id| id.parse::<i32>().ok()) {
+        id
+    } else {
+        redirect!(res, ctx, settings_url, "Invalid data");
+    };
+    db::delete::branch_rule(pool, repo, id)?;
+    redirect!(res, ctx, settings_url, "Branch protection removed");
+}}
+
+// POST /{user}/{repo}/settings/transfer
+route!{transfer, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let user = req.get_param("user");
+    let reponame = req.get_param("repo");
+    let settings_url = format!("{}/{}/settings", user, reponame);
+
+    check_repo_admin!(current, user, reponame, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let repo = if let Some(repo) = db::read::repo_id(pool, &user, &reponame)? {
+        repo
+    } else {
+        return not_found(req, res, ctx);
+    };
+
+    let new_owner = req.form_value("owner").unwrap_or_default();
+    if !db::read::user_exists(pool, &new_owner)? {
+        redirect!(res, ctx, settings_url, "New owner does not exist");
+    }
+    if new_owner == user {
+        redirect!(res, ctx, settings_url, "The repo already belongs to that owner");
+    }
+    if db::read::repo_exists(pool, &new_owner, &reponame)? {
+        redirect!(res, ctx, settings_url, "New owner already has a repo with that name");
+    }
+
+    // The transfer only happens once the new owner accepts it
+    let new_owner_id = db::read::user_id(pool, &new_owner)?;
+    db::create::repo_transfer(pool, repo, new_owner_id)?;
+    redirect!(res, ctx, settings_url, "Transfer requested, it will complete once the new owner accepts it");
+}}
+
+// POST /{user}/{repo}/settings/transfer/cancel
+route!{cancel_transfer, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+
+    let user = req.get_param("user");
+    let reponame = req.get_param("repo");
+    let settings_url = format!("{}/{}/settings", user, reponame);
+
+    check_repo_admin!(current, user, reponame, res, ctx);
+
+    let pool = &ctx.db_pool;
+    let repo = if let Some(repo) = db::read::repo_id(pool, &user, &reponame)? {
+        repo
+    } else {
+        return not_found(req, res, ctx);
+    };
+
+    if let Some(transfer) = db::read::outgoing_transfer(pool, repo)? {
+        db::delete::repo_transfer(pool, transfer.id)?;
+    }
+    redirect!(res, ctx, settings_url, "Transfer cancelled");
 }}
 
 // POST /{user}/{repo}/delete
 route!{delete, req, res, ctx, {
     let cookies = req.get_cookies();
-    let username = check_login!(&cookies, res, ctx);
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
 
     let user = req.get_param("user");
     let reponame = req.get_param("repo");
 
-    // TODO: we probably want a different check here
-    if username != user {
-        redirect!(res, ctx, format!("{}/{}", user, reponame), "You must own a repo to delete it");
-    }
+    check_repo_admin!(current, user, reponame, res, ctx);
 
     if let Some(name) = req.form_value("delete") {
         if name != reponame {
@@ -117,7 +375,39 @@ route!{delete, req, res, ctx, {
     }
 
     let pool = &ctx.db_pool;
-    db::delete::repo(pool, username, &reponame)?;
-    git::delete(ctx, username, &reponame)?;
-    redirect!(res, ctx, format!("{}", username), "Repo deleted");
+    db::delete::repo(pool, &user, &reponame)?;
+    git::delete(ctx, &user, &reponame)?;
+    redirect!(res, ctx, format!("{}", user), "Repo deleted");
+}}
+
+// POST /settings/transfers/{id}/accept
+route!{accept_transfer, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let transfer = read_transfer!(current, req, res, ctx);
+
+    let pool = &ctx.db_pool;
+    if db::read::repo_exists(pool, &transfer.new_owner, &transfer.name)? {
+        redirect!(res, ctx, "settings", "You already have a repo with that name");
+    }
+
+    git::transfer(ctx, &transfer.owner, &transfer.new_owner, &transfer.name)?;
+    if let Err(e) = db::update::transfer_repo(pool, transfer.repo, transfer.new_owner_id) {
+        // Put the repo back so that the database and disk stay consistent
+        git::transfer(ctx, &transfer.new_owner, &transfer.owner, &transfer.name)?;
+        return Err(e);
+    }
+    redirect!(res, ctx, format!("{}/{}", transfer.new_owner, transfer.name), "Repo transferred");
+}}
+
+// POST /settings/transfers/{id}/decline
+route!{decline_transfer, req, res, ctx, {
+    let cookies = req.get_cookies();
+    let current = check_login!(req, &cookies, res, ctx);
+    check_csrf!(req, &cookies, res, ctx);
+    let transfer = read_transfer!(current, req, res, ctx);
+
+    db::delete::repo_transfer(&ctx.db_pool, transfer.id)?;
+    redirect!(res, ctx, "settings", "Transfer declined");
 }}
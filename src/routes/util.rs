@@ -1,55 +1,426 @@
-use Context;
+use {db, git, ldap, totp, Context, LdapDirectory, Result};
+use types::{CurrentUser, Login, NewUser, PendingLogin, PendingOidc, PendingOidcLink, PendingTotp};
 
 use chrono::Duration;
-use hayaku::{Cookie, CookieJar};
-
-pub fn check_login<'a>(ctx: &Context, cookies: &'a CookieJar) -> Option<&'a str> {
-    if let Some(cookie) = cookies.get("session_key") {
-        if let Some(session) = ctx.logins.lock().unwrap().read(cookie.value()) {
-            let name: String = session.metadata().unwrap();
-            if let Some(cookie) = cookies.get("dotcom_user") {
-                if cookie.value() == name {
-                    return Some(cookie.value());
+use hayaku::{header, Cookie, CookieJar, Request};
+use rand::{self, Rng};
+use rand::distributions::Alphanumeric;
+use sha2::{Digest, Sha256};
+
+use std::net::IpAddr;
+use std::time::{self as stdtime, Instant};
+
+/// Sessions expire after this many days without being used.
+const SESSION_DAYS: i64 = 30;
+/// How long a user has to enter their second factor after their password.
+const PENDING_LOGIN_SECS: u64 = 300;
+/// How long users have to add a new TOTP secret to their app.
+const PENDING_TOTP_SECS: u64 = 900;
+
+fn sha256_hex(input: &str) -> String {
+    let hash = Sha256::digest(input.as_bytes());
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Sessions are listed and revoked by a hash of their key, so that the key
+/// itself never has to be stored.
+pub fn session_id(key: &str) -> String {
+    sha256_hex(&format!("session:{}", key))
+}
+
+/// The id of the session of this request.
+pub fn current_session(cookies: &CookieJar) -> String {
+    session_id(cookies.get("session_key").map(|c| c.value()).unwrap_or_default())
+}
+
+/// Looks up the user logged in with the session of this request. Revoked
+/// sessions are removed from the database, so a session is only valid while
+/// its row exists and hasn't expired. Every use pushes back the expiry. With
+/// proxy authentication the session ends once the proxy sends another user.
+pub fn current_user(req: &Request, ctx: &Context, cookies: &CookieJar) -> Option<CurrentUser> {
+    let key = try_opt!(cookies.get("session_key"));
+    let id = session_id(key.value());
+    match db::read::session_user(&ctx.db_pool, &id) {
+        Ok(Some(user)) => {
+            // The proxy now vouches for someone else, so this session is over
+            if proxy_username(req, ctx).map_or(false, |name| name != user.username) {
+                if let Err(e) = db::delete::session(&ctx.db_pool, &id) {
+                    warn!("unable to end session: {}", e);
                 }
+                return None;
             }
+            if let Err(e) = db::update::session_seen(&ctx.db_pool, &id, Duration::days(SESSION_DAYS)) {
+                warn!("unable to update session: {}", e);
+            }
+            Some(user)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            warn!("unable to read session: {}", e);
+            None
         }
     }
-    None
 }
 
-pub fn login(username: String, cookies: &mut CookieJar, ctx: &Context) {
-    let key = ctx.logins.lock().unwrap().generate(Duration::days(30), username.clone());
+/// Logs in `user` with a new session. Any session the request already had is
+/// discarded, so a session key planted before login can't be used after it.
+pub fn login(req: &Request, user: i32, cookies: &mut CookieJar, ctx: &Context) -> Result<()> {
+    if let Some(cookie) = req.get_cookies().get("session_key") {
+        db::delete::session(&ctx.db_pool, &session_id(cookie.value()))?;
+    }
+    db::delete::expired_sessions(&ctx.db_pool)?;
+
+    let key: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect();
+
+    let user_agent = req.headers().get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let ip = remote_ip(req).map(|ip| ip.to_string()).unwrap_or_default();
+    db::create::session(&ctx.db_pool, &session_id(&key), user, user_agent, &ip,
+                        Duration::days(SESSION_DAYS))?;
 
+    // Expiry is enforced by the server, the cookie only has to outlive it
     let cookie = Cookie::build("session_key", key)
         .secure(true)
         .http_only(true)
         .path("/")
-        .max_age(time::Duration::days(30))
+        .max_age(time::Duration::days(365))
         .finish();
     cookies.add(cookie);
+    Ok(())
+}
+
+pub fn logout(req_cookies: &CookieJar, res_cookies: &mut CookieJar, ctx: &Context) {
+    if let Some(cookie) = req_cookies.get("session_key") {
+        if let Err(e) = db::delete::session(&ctx.db_pool, &session_id(cookie.value())) {
+            warn!("unable to remove session: {}", e);
+        }
+        let del_cookie = Cookie::build("session_key", "")
+            .max_age(time::Duration::seconds(0))
+            .expires(time::OffsetDateTime::UNIX_EPOCH)
+            .finish();
+        res_cookies.add(del_cookie);
+    }
+}
+
+/// The CSRF token for the session of this request. Every form submitted by a
+/// logged in user has to include it as `csrf_token`.
+pub fn csrf_token(cookies: &CookieJar) -> String {
+    match cookies.get("session_key") {
+        Some(cookie) => sha256_hex(&format!("csrf:{}", cookie.value())),
+        None => String::new(),
+    }
+}
+
+pub fn valid_csrf(cookies: &CookieJar, token: &str) -> bool {
+    let expected = csrf_token(cookies);
+    // Compare in constant time so the token can't be guessed byte by byte
+    !expected.is_empty() && expected.len() == token.len() &&
+        expected.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
 
-    let cookie = Cookie::build("dotcom_user", username)
+/// Starts a login that still needs a second factor. The user is only logged in
+/// once `finish_pending_login` is called.
+pub fn begin_pending_login(username: String, cookies: &mut CookieJar, ctx: &Context) {
+    let key: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+
+    let now = Instant::now();
+    let mut pending = ctx.pending_logins.lock().unwrap();
+    pending.retain(|_, login| login.expires > now);
+    pending.insert(key.clone(), PendingLogin {
+        username: username,
+        expires: now + stdtime::Duration::from_secs(PENDING_LOGIN_SECS),
+    });
+
+    let cookie = Cookie::build("pending_login", key)
         .secure(true)
         .http_only(true)
         .path("/")
-        .max_age(time::Duration::days(30))
+        .max_age(time::Duration::seconds(PENDING_LOGIN_SECS as i64))
         .finish();
     cookies.add(cookie);
 }
 
-pub fn logout(req_cookies: &CookieJar, res_cookies: &mut CookieJar, ctx: &Context) {
-    if let Some(cookie) = req_cookies.get("session_key") {
-        ctx.logins.lock().unwrap().remove(cookie.value());
-        let del_cookie = Cookie::build("session_key", "")
+/// Returns the username of the pending login for this request, if it hasn't
+/// expired.
+pub fn pending_login(ctx: &Context, cookies: &CookieJar) -> Option<String> {
+    let cookie = try_opt!(cookies.get("pending_login"));
+    let pending = ctx.pending_logins.lock().unwrap();
+    let login = try_opt!(pending.get(cookie.value()));
+    if login.expires > Instant::now() {
+        Some(login.username.clone())
+    } else {
+        None
+    }
+}
+
+pub fn finish_pending_login(req_cookies: &CookieJar, res_cookies: &mut CookieJar, ctx: &Context) {
+    if let Some(cookie) = req_cookies.get("pending_login") {
+        ctx.pending_logins.lock().unwrap().remove(cookie.value());
+        let del_cookie = Cookie::build("pending_login", "")
             .max_age(time::Duration::seconds(0))
             .expires(time::OffsetDateTime::UNIX_EPOCH)
             .finish();
         res_cookies.add(del_cookie);
+    }
+}
+
+/// Generates a TOTP secret for `user` to set up, replacing any earlier one.
+pub fn begin_totp_setup(ctx: &Context, user: i32) -> String {
+    let secret = totp::generate_secret();
+    let now = Instant::now();
+    let mut pending = ctx.pending_totp.lock().unwrap();
+    pending.retain(|_, setup| setup.expires > now);
+    pending.insert(user, PendingTotp {
+        secret: secret.clone(),
+        expires: now + stdtime::Duration::from_secs(PENDING_TOTP_SECS),
+    });
+    secret
+}
+
+/// Returns the TOTP secret `user` is setting up, if it hasn't expired.
+pub fn pending_totp(ctx: &Context, user: i32) -> Option<String> {
+    let pending = ctx.pending_totp.lock().unwrap();
+    let setup = try_opt!(pending.get(&user));
+    if setup.expires > Instant::now() {
+        Some(setup.secret.clone())
+    } else {
+        None
+    }
+}
+
+pub fn finish_totp_setup(ctx: &Context, user: i32) {
+    ctx.pending_totp.lock().unwrap().remove(&user);
+}
+
+/// Where the OpenID Connect provider sends users back to after they logged in.
+pub fn oidc_redirect_uri(ctx: &Context) -> String {
+    format!("{}{}login/oidc/callback", ctx.url, ctx.mount)
+}
+
+/// Remembers an OpenID Connect login until the provider redirects back. The
+/// state is also stored in a cookie so that only the browser that started the
+/// login can finish it.
+pub fn begin_oidc_login(state: String, pending: PendingOidc, cookies: &mut CookieJar, ctx: &Context) {
+    let now = Instant::now();
+    let max_age = pending.expires.saturating_duration_since(now).as_secs() as i64;
+    let mut logins = ctx.oidc_logins.lock().unwrap();
+    logins.retain(|_, login| login.expires > now);
+    logins.insert(state.clone(), pending);
+
+    let cookie = Cookie::build("oidc_state", state)
+        .secure(true)
+        .http_only(true)
+        .path("/")
+        .max_age(time::Duration::seconds(max_age))
+        .finish();
+    cookies.add(cookie);
+}
+
+/// Takes the pending OpenID Connect login for `state`, if it was started by
+/// this browser and hasn't expired.
+pub fn take_oidc_login(ctx: &Context, req_cookies: &CookieJar, res_cookies: &mut CookieJar, state: &str)
+    -> Option<PendingOidc>
+{
+    let cookie = try_opt!(req_cookies.get("oidc_state"));
+    let del_cookie = Cookie::build("oidc_state", "")
+        .max_age(time::Duration::seconds(0))
+        .expires(time::OffsetDateTime::UNIX_EPOCH)
+        .finish();
+    res_cookies.add(del_cookie);
+
+    if cookie.value() != state {
+        return None;
+    }
+    let pending = try_opt!(ctx.oidc_logins.lock().unwrap().remove(state));
+    if pending.expires > Instant::now() {
+        Some(pending)
+    } else {
+        None
+    }
+}
+
+/// Starts linking an OpenID Connect identity to an existing account. The link
+/// is only made once `oidc_link` has been confirmed with the account's
+/// password.
+pub fn begin_oidc_link(link: PendingOidcLink, cookies: &mut CookieJar, ctx: &Context) {
+    let key: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
 
-        let del_cookie = Cookie::build("dotcom_user", "")
+    let now = Instant::now();
+    let mut links = ctx.oidc_links.lock().unwrap();
+    links.retain(|_, link| link.expires > now);
+    links.insert(key.clone(), link);
+
+    let cookie = Cookie::build("oidc_link", key)
+        .secure(true)
+        .http_only(true)
+        .path("/")
+        .max_age(time::Duration::seconds(PENDING_LOGIN_SECS as i64))
+        .finish();
+    cookies.add(cookie);
+}
+
+/// Returns the identity this request is linking, if it hasn't expired.
+pub fn oidc_link(ctx: &Context, cookies: &CookieJar) -> Option<PendingOidcLink> {
+    let cookie = try_opt!(cookies.get("oidc_link"));
+    let links = ctx.oidc_links.lock().unwrap();
+    let link = try_opt!(links.get(cookie.value()));
+    if link.expires > Instant::now() {
+        Some(link.clone())
+    } else {
+        None
+    }
+}
+
+pub fn finish_oidc_link(req_cookies: &CookieJar, res_cookies: &mut CookieJar, ctx: &Context) {
+    if let Some(cookie) = req_cookies.get("oidc_link") {
+        ctx.oidc_links.lock().unwrap().remove(cookie.value());
+        let del_cookie = Cookie::build("oidc_link", "")
             .max_age(time::Duration::seconds(0))
             .expires(time::OffsetDateTime::UNIX_EPOCH)
             .finish();
         res_cookies.add(del_cookie);
     }
 }
+
+/// Returns an organization that `user` is the only owner of, if any. Such an
+/// account can't be deleted, as the organization would be left without an
+/// owner.
+pub fn sole_owned_org(pool: &db::Pool, user: i32) -> Result<Option<String>> {
+    for org in db::read::owned_orgs(pool, user)? {
+        let org_id = db::read::user_id(pool, &org)?;
+        if db::read::org_members(pool, org_id)?.iter().filter(|m| m.is_owner).count() == 1 {
+            return Ok(Some(org));
+        }
+    }
+    Ok(None)
+}
+
+/// Checks a username and password, returning the id of the account they
+/// belong to. If an LDAP directory is configured it is asked first, and
+/// directory users get an account the first time they log in. Everyone else is
+/// checked against their local password.
+pub fn check_password(ctx: &Context, login: &Login) -> Result<Option<i32>> {
+    let pool = &ctx.db_pool;
+    if let Some(ref config) = ctx.ldap {
+        // Local users can still log in while the directory is unreachable
+        match ldap_login(ctx, config, login) {
+            Ok(Some(id)) if db::read::user_disabled(pool, id)?.unwrap_or(true) => return Ok(None),
+            Ok(Some(id)) => return Ok(Some(id)),
+            Ok(None) => (),
+            Err(e) => warn!("could not check {} against the directory: {}", login.username, e),
+        }
+        // Directory users may only log in with their directory password
+        if db::read::is_ldap_user(pool, &login.username)? {
+            return Ok(None);
+        }
+    }
+    if db::read::check_login(pool, login)? {
+        Ok(Some(db::read::user_id(pool, &login.username)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Checks a login against the directory, returning the id of the account it
+/// belongs to.
+fn ldap_login(ctx: &Context, config: &LdapDirectory, login: &Login) -> Result<Option<i32>> {
+    match ldap::authenticate(config, &login.username, &login.password)? {
+        Some(user) => ldap::sync_user(ctx, config, &user),
+        None => Ok(None),
+    }
+}
+
+/// Reads the credentials from an HTTP Basic `Authorization` header.
+pub fn basic_auth(req: &Request) -> Option<Login> {
+    use base64::engine::general_purpose;
+    use base64::Engine;
+
+    let value = try_opt!(req.headers().get(header::AUTHORIZATION));
+    let value = try_opt!(value.to_str().ok());
+    if !value.starts_with("Basic ") {
+        return None;
+    }
+
+    let decoded = try_opt!(general_purpose::STANDARD.decode(&value[6..]).ok());
+    let decoded = try_opt!(String::from_utf8(decoded).ok());
+    let mut parts = decoded.splitn(2, ':');
+    let username = try_opt!(parts.next()).to_string();
+    let password = try_opt!(parts.next()).to_string();
+    Some(Login {
+        username: username,
+        password: password,
+    })
+}
+
+/// Returns the user authenticated by a trusted SSO proxy, creating their
+/// account the first time they are seen.
+pub fn proxy_user(req: &Request, ctx: &Context) -> Result<Option<i32>> {
+    let proxy = match ctx.proxy_auth {
+        Some(ref proxy) => proxy,
+        None => return Ok(None),
+    };
+    let username = match proxy_username(req, ctx) {
+        Some(username) => username,
+        None => return Ok(None),
+    };
+
+    let pool = &ctx.db_pool;
+    if !db::read::user_exists(pool, &username)? {
+        let email = proxy.email_header.as_ref()
+            .and_then(|h| proxy_header(req, h))
+            .unwrap_or_default();
+        let new_user = match NewUser::external(username.clone(), email) {
+            Some(user) => user,
+            None => {
+                warn!("proxy sent an invalid username: {}", username);
+                return Ok(None);
+            }
+        };
+        db::create::user(pool, &new_user)?;
+        git::create_user(ctx, &username)?;
+        info!("created user {} on first proxy login", username);
+    } else if db::read::is_org(pool, &username)? {
+        return Ok(None);
+    }
+
+    let id = db::read::user_id(pool, &username)?;
+    if db::read::user_disabled(pool, id)?.unwrap_or(true) {
+        return Ok(None);
+    }
+    Ok(Some(id))
+}
+
+/// Returns the username the trusted proxy sent along with `req`, if any.
+fn proxy_username(req: &Request, ctx: &Context) -> Option<String> {
+    let proxy = try_opt!(ctx.proxy_auth.as_ref());
+    // Anyone can set the header, so it only counts when it came through the proxy
+    match remote_ip(req) {
+        Some(ip) if proxy.proxies.contains(&ip) => proxy_header(req, &proxy.header),
+        _ => None,
+    }
+}
+
+fn proxy_header(req: &Request, name: &str) -> Option<String> {
+    req.headers().get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// The address of the client that sent `req`.
+pub fn remote_ip(req: &Request) -> Option<IpAddr> {
+    req.remote_addr().map(|addr| addr.ip())
+}
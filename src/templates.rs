@@ -2,6 +2,7 @@ use Context;
 use types::*;
 
 use std::fmt::Display;
+use std::sync::atomic::Ordering;
 
 #[derive(BartDisplay)]
 #[template = "templates/head.html"]
@@ -81,15 +82,17 @@ pub struct Navbar<'a, 'b> {
     pub mount: &'a str,
     pub signup: bool,
     pub username: Option<&'b str>,
+    pub is_admin: bool,
 }
 
 impl<'a, 'b> Navbar<'a, 'b> {
-    pub fn new(ctx: &'a Context, username: Option<&'b str>) -> Self {
+    pub fn new(ctx: &'a Context, user: Option<&'b CurrentUser>) -> Self {
         Navbar {
             name: &ctx.name,
             mount: &ctx.mount,
-            signup: ctx.signup,
-            username: username,
+            signup: ctx.signup.load(Ordering::Relaxed),
+            username: user.map(|u| u.username.as_str()),
+            is_admin: user.map_or(false, |u| u.is_admin),
         }
     }
 }
@@ -98,6 +101,66 @@ impl<'a, 'b> Navbar<'a, 'b> {
 #[template = "templates/home.html"]
 pub struct HomeTmpl;
 
+#[derive(BartDisplay)]
+#[template = "templates/user/signup.html"]
+pub struct SignupTmpl {
+    pub invite: Option<String>,
+    pub domains: String,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/user/totp_setup.html"]
+pub struct TotpSetupTmpl<'a, 'b> {
+    pub mount: &'a str,
+    pub issuer: &'a str,
+    pub username: &'b str,
+    pub secret: String,
+    pub csrf: String,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/user/recovery_codes.html"]
+pub struct RecoveryCodesTmpl<'a> {
+    pub mount: &'a str,
+    pub codes: Vec<String>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/user/login.html"]
+pub struct LoginTmpl<'a> {
+    pub mount: &'a str,
+    /// The name of the OpenID Connect provider, if one is configured
+    pub oidc: Option<&'a str>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/user/oidc_link.html"]
+pub struct OidcLinkTmpl<'a> {
+    pub username: String,
+    pub provider: &'a str,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/user/logout.html"]
+pub struct LogoutTmpl<'a> {
+    pub mount: &'a str,
+    pub csrf: String,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/user/org_new.html"]
+pub struct OrgNewTmpl {
+    pub csrf: String,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/user/sessions.html"]
+pub struct SessionsTmpl<'a> {
+    pub mount: &'a str,
+    pub sessions: Vec<UserSession>,
+    pub csrf: String,
+}
+
 #[derive(BartDisplay)]
 #[template = "templates/explore.html"]
 pub struct ExploreTmpl<'a> {
@@ -111,15 +174,53 @@ pub struct UserTmpl<'a, 'b> {
     pub mount: &'a str,
     pub username: &'b str,
     pub repos: Vec<Repo>,
+    pub is_org: bool,
+    pub members: Vec<OrgMember>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/user/repo_new.html"]
+pub struct RepoNewTmpl<'a> {
+    pub username: &'a str,
+    pub orgs: Vec<String>,
+    pub csrf: String,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/org/settings.html"]
+pub struct OrgSettingsTmpl<'a, 'b> {
+    pub mount: &'a str,
+    pub org: &'b str,
+    pub members: Vec<OrgMember>,
+    pub teams: Vec<Team>,
+    pub csrf: String,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/org/team.html"]
+pub struct TeamTmpl<'a, 'b> {
+    pub mount: &'a str,
+    pub org: &'b str,
+    pub team: Team,
+    pub members: Vec<String>,
+    pub repos: Vec<String>,
+    pub csrf: String,
 }
 
 #[derive(BartDisplay)]
 #[template = "templates/user/settings.html"]
 pub struct UserSettings<'a, 'b> {
+    pub url: &'a str,
     pub mount: &'a str,
     pub username: &'b str,
     pub email: String,
     pub keys: Vec<SshKey>,
+    pub signing_keys: Vec<SigningKey>,
+    pub transfers: Vec<RepoTransfer>,
+    pub invites: Vec<Invite>,
+    pub totp_enabled: bool,
+    pub recovery_codes_left: i64,
+    pub csrf: String,
 }
 
 #[derive(BartDisplay)]
@@ -148,6 +249,25 @@ pub struct CommitTmpl<'a, 'b> {
     pub commit: Commit,
     pub items: Vec<RepoItem>,
     pub readme: Option<String>,
+    pub contained_in: Vec<RefBadge>,
+    /// Whether there were too many refs to check them all for the commit
+    pub more_refs: bool,
+    pub parents: Vec<CommitParent>,
+    pub files: Vec<DiffFile>,
+    pub additions: usize,
+    pub deletions: usize,
+}
+
+impl<'a, 'b> CommitTmpl<'a, 'b> {
+    /// Whether the refs containing the commit are worth a line, they may be
+    /// unknown if there were too many to check.
+    pub fn show_contained_in(&self) -> bool {
+        !self.contained_in.is_empty() || self.more_refs
+    }
+
+    pub fn is_merge(&self) -> bool {
+        self.parents.len() > 1
+    }
 }
 
 #[derive(BartDisplay)]
@@ -156,6 +276,10 @@ pub struct RepoSettingsTmpl<'a, 'b> {
     pub mount: &'a str,
     pub username: &'b str,
     pub repo: Repo,
+    pub collaborators: Vec<Collaborator>,
+    pub branch_rules: Vec<BranchRule>,
+    pub transfer: Option<RepoTransfer>,
+    pub csrf: String,
 }
 
 #[derive(BartDisplay)]
@@ -164,8 +288,14 @@ pub struct RefsTmpl<'a, 'b> {
     pub mount: &'a str,
     pub username: &'b str,
     pub repo: Repo,
-    pub branches: Vec<Branch>,
-    pub tags: Vec<Tag>,
+    pub default_branch: String,
+    pub refs: Vec<RefInfo>,
+    pub by_date: bool,
+    pub filter: String,
+    pub page: usize,
+    pub pages: usize,
+    pub prev: Option<usize>,
+    pub next: Option<usize>,
 }
 
 
@@ -177,8 +307,53 @@ pub struct RepoLogTmpl<'a, 'b> {
     pub repo: Repo,
     pub id: String,
     pub log: Vec<Commit>,
-    pub commits: usize,
+    /// The total number of commits, unless it's still being counted
+    pub commits: Option<usize>,
+    /// The commit the next page starts after
+    pub next: Option<String>,
+    pub prev: Option<usize>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/repo/compare.html"]
+pub struct CompareTmpl<'a, 'b> {
+    pub mount: &'a str,
+    pub username: &'b str,
+    pub repo: Repo,
+    pub base: &'b str,
+    pub head: &'b str,
+    pub merge_base: Option<Commit>,
+    /// The newest of the commits in head but not in base
+    pub commits: Vec<Commit>,
+    pub total: usize,
+    /// Whether there are more than `total` commits, counting stops at a limit
+    pub more: bool,
+    pub files: Vec<DiffFile>,
+    pub additions: usize,
+    pub deletions: usize,
+    /// For the ref pickers
+    pub branches: Vec<Branch>,
+    pub tags: Vec<Tag>,
+}
+
+impl<'a, 'b> CompareTmpl<'a, 'b> {
+    pub fn truncated(&self) -> bool {
+        self.total > self.commits.len()
+    }
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/repo/history.html"]
+pub struct RepoHistoryTmpl<'a, 'b> {
+    pub mount: &'a str,
+    pub username: &'b str,
+    pub repo: Repo,
+    pub id: &'b str,
+    pub filename: &'b str,
+    pub log: Vec<Commit>,
+    /// The commit the next page starts after
     pub next: Option<String>,
+    pub prev: Option<usize>,
 }
 
 #[derive(BartDisplay)]
@@ -189,6 +364,39 @@ pub struct RepoSrcTmpl<'a, 'b, 'c> {
     pub username: &'b str,
     pub repo: Repo,
     pub path: Vec<(String, String)>,
+    pub refname: &'c str,
     pub filename: &'c str,
     pub src: RepoSrc,
 }
+
+#[derive(BartDisplay)]
+#[template = "templates/repo/blame.html"]
+pub struct BlameTmpl<'a, 'b> {
+    pub mount: &'a str,
+    pub username: &'b str,
+    pub repo: Repo,
+    pub refname: &'b str,
+    pub filename: &'b str,
+    /// Files over the size limit aren't blamed
+    pub too_large: bool,
+    pub groups: Vec<BlameGroup>,
+}
+
+#[derive(BartDisplay)]
+#[template = "templates/admin.html"]
+pub struct AdminTmpl<'a> {
+    pub mount: &'a str,
+    pub signup: bool,
+    /// Whether an LDAP directory is configured
+    pub ldap: bool,
+    pub num_users: usize,
+    pub num_orgs: usize,
+    pub num_repos: usize,
+    pub disk_usage: String,
+    pub users: Vec<AdminUser>,
+    pub repos: Vec<AdminRepo>,
+    pub invites: Vec<Invite>,
+    pub lockouts: Vec<Throttle>,
+    pub auth_events: Vec<AuthEvent>,
+    pub csrf: String,
+}
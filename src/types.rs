@@ -1,9 +1,131 @@
 use Result;
 
 use bcrypt::{self, DEFAULT_COST};
+use chrono::{Duration, NaiveDateTime, Utc};
 use hayaku::Request;
+use rand::{self, Rng};
+use rand::distributions::Alphanumeric;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use sha2::{Digest, Sha256};
 
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::mpsc::SyncSender;
+use std::time::Instant;
+
+/// Names which would collide with a top level route if used as a user or
+/// organization name.
+const RESERVED_NAMES: &[&str] = &["admin", "explore", "highlight.css", "login", "logout", "org",
+                                  "repo", "resources", "settings", "signup"];
+
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.starts_with('.') &&
+        !RESERVED_NAMES.contains(&name)
+}
+
+/// Checks that `email` belongs to one of `domains`, an empty list allows any
+/// address.
+pub fn email_domain_allowed(email: &str, domains: &[String]) -> bool {
+    if domains.is_empty() {
+        return true;
+    }
+
+    match email.rsplit_once('@') {
+        Some((local, domain)) if !local.is_empty() => {
+            domains.iter().any(|d| d.eq_ignore_ascii_case(domain))
+        }
+        _ => false,
+    }
+}
+
+/// The permission a user has on a repository. Variants are ordered so that
+/// permissions can be compared with `<` and `>=`.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Access {
+    None,
+    Read,
+    Write,
+    Admin,
+}
+
+impl Access {
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Access::Read),
+            "write" => Some(Access::Write),
+            "admin" => Some(Access::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Access::None => "none",
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Admin => "admin",
+        }
+    }
+}
+
+/// Who can see a repository. Internal repos are visible to any logged in
+/// user, private repos only to users that have been granted access.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Visibility {
+    Public,
+    Internal,
+    Private,
+}
+
+impl Visibility {
+    pub fn new(name: &str) -> Option<Self> {
+        match name {
+            "public" => Some(Visibility::Public),
+            "internal" => Some(Visibility::Internal),
+            "private" => Some(Visibility::Private),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Visibility::Public => "public",
+            Visibility::Internal => "internal",
+            Visibility::Private => "private",
+        }
+    }
+}
+
+impl ToSql for Visibility {
+    fn to_sql(&self) -> ::rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::from(self.name()))
+    }
+}
+
+impl FromSql for Visibility {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        Visibility::new(value.as_str()?).ok_or(FromSqlError::InvalidType)
+    }
+}
+
+impl ToSql for Access {
+    fn to_sql(&self) -> ::rusqlite::Result<ToSqlOutput> {
+        Ok(ToSqlOutput::from(*self as i64))
+    }
+}
+
+impl FromSql for Access {
+    fn column_result(value: ValueRef) -> FromSqlResult<Self> {
+        match value.as_i64()? {
+            0 => Ok(Access::None),
+            1 => Ok(Access::Read),
+            2 => Ok(Access::Write),
+            3 => Ok(Access::Admin),
+            v => Err(FromSqlError::OutOfRange(v)),
+        }
+    }
+}
+
 pub struct NewUser {
     pub username: String,
     pub email: String,
@@ -12,11 +134,13 @@ pub struct NewUser {
 }
 
 impl NewUser {
-    pub fn new(req: &mut Request) -> Option<Self> {
+    /// Reads a new user from the signup form, `domains` restricts which email
+    /// addresses may be used.
+    pub fn new(req: &mut Request, domains: &[String]) -> Option<Self> {
         let (username, email) = form_values!(req, "username", "email");
         let (password, confirm) = form_values!(req, "password", "password_confirm");
 
-        if password != confirm {
+        if password != confirm || !valid_name(&username) || !email_domain_allowed(&email, domains) {
             return None;
         }
 
@@ -28,6 +152,202 @@ impl NewUser {
             num_repos: 0,
         })
     }
+
+    /// A user whose identity is managed outside of Valentine. They get a
+    /// random password so that they can't log in with one.
+    pub fn external(username: String, email: String) -> Option<Self> {
+        if !valid_name(&username) {
+            return None;
+        }
+
+        let password: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let password_hash = try_opt!(bcrypt::hash(&password, DEFAULT_COST).ok());
+        Some(NewUser {
+            username: username,
+            email: email,
+            password: password_hash,
+            num_repos: 0,
+        })
+    }
+}
+
+pub struct PasswordChange {
+    pub old_password: String,
+    /// The bcrypt hash of the new password
+    pub password: String,
+}
+
+impl PasswordChange {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        let old_password = try_opt!(req.form_value("old_password"));
+        let (password, confirm) = form_values!(req, "password", "password_confirm");
+
+        if password.is_empty() || password != confirm {
+            return None;
+        }
+
+        let password_hash = try_opt!(bcrypt::hash(&password, DEFAULT_COST).ok());
+        Some(PasswordChange {
+            old_password: old_password,
+            password: password_hash,
+        })
+    }
+}
+
+pub struct NewOrg {
+    pub name: String,
+    pub email: String,
+}
+
+impl NewOrg {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        let name = try_opt!(req.form_value("name"));
+        let email = req.form_value("email").unwrap_or_default();
+
+        if !valid_name(&name) {
+            return None;
+        }
+
+        Some(NewOrg {
+            name: name,
+            email: email,
+        })
+    }
+}
+
+pub struct OrgMember {
+    pub username: String,
+    pub is_owner: bool,
+}
+
+pub struct Team {
+    pub id: i32,
+    pub name: String,
+    pub access: Access,
+}
+
+pub struct NewTeam {
+    pub org: i32,
+    pub name: String,
+    pub access: Access,
+}
+
+impl NewTeam {
+    pub fn new(req: &mut Request, org: i32) -> Option<Self> {
+        let (name, access) = form_values!(req, "name", "access");
+        let access = try_opt!(Access::new(&access));
+
+        if !valid_name(&name) {
+            return None;
+        }
+
+        Some(NewTeam {
+            org: org,
+            name: name,
+            access: access,
+        })
+    }
+}
+
+pub struct Collaborator {
+    pub username: String,
+    pub access: Access,
+}
+
+/// A pending transfer of `owner/name` to `new_owner`, waiting for the new
+/// owner to accept it.
+pub struct RepoTransfer {
+    pub id: i32,
+    pub repo: i64,
+    pub owner: String,
+    pub name: String,
+    pub new_owner_id: i32,
+    pub new_owner: String,
+}
+
+pub struct BranchRule {
+    pub id: i32,
+    pub pattern: String,
+    pub no_force_push: bool,
+    pub no_deletion: bool,
+    pub require_signed: bool,
+    pub users: Vec<String>,
+    pub teams: Vec<String>,
+}
+
+impl BranchRule {
+    /// Pushes are restricted to the listed users and teams, if there are any.
+    pub fn restricted(&self) -> bool {
+        !self.users.is_empty() || !self.teams.is_empty()
+    }
+
+    pub fn pushers(&self) -> String {
+        let mut pushers = self.users.clone();
+        pushers.extend(self.teams.iter().map(|t| format!("team {}", t)));
+        pushers.join(", ")
+    }
+
+    pub fn matches(&self, branch: &str) -> bool {
+        glob_match(self.pattern.as_bytes(), branch.as_bytes())
+    }
+}
+
+/// Matches `name` against `pattern`, where `*` matches anything but `/` and
+/// `**` matches anything.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    if pattern.starts_with(b"**") {
+        let rest = &pattern[2..];
+        (0..name.len() + 1).any(|i| glob_match(rest, &name[i..]))
+    } else if pattern.starts_with(b"*") {
+        let rest = &pattern[1..];
+        let end = name.iter().position(|&c| c == b'/').unwrap_or(name.len());
+        (0..end + 1).any(|i| glob_match(rest, &name[i..]))
+    } else if let Some((&p, rest)) = pattern.split_first() {
+        name.first() == Some(&p) && glob_match(rest, &name[1..])
+    } else {
+        name.is_empty()
+    }
+}
+
+pub struct NewBranchRule {
+    pub pattern: String,
+    pub no_force_push: bool,
+    pub no_deletion: bool,
+    pub require_signed: bool,
+    pub users: Vec<String>,
+    pub teams: Vec<String>,
+}
+
+impl NewBranchRule {
+    pub fn new(req: &mut Request) -> Option<Self> {
+        let pattern = try_opt!(req.form_value("pattern"));
+        let split = |list: Option<String>| {
+            list.unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<_>>()
+        };
+        let users = split(req.form_value("users"));
+        let teams = split(req.form_value("teams"));
+
+        if pattern.is_empty() {
+            return None;
+        }
+
+        Some(NewBranchRule {
+            pattern: pattern,
+            no_force_push: req.form_value("no_force_push") == Some(String::from("on")),
+            no_deletion: req.form_value("no_deletion") == Some(String::from("on")),
+            require_signed: req.form_value("require_signed") == Some(String::from("on")),
+            users: users,
+            teams: teams,
+        })
+    }
 }
 
 pub struct Login {
@@ -50,14 +370,14 @@ pub struct Repo {
     pub name: String,
     pub description: String,
     pub owner: i32,
-    pub private: bool,
+    pub visibility: Visibility,
 }
 
 impl Repo {
     pub fn new(req: &mut Request, owner: i32) -> Option<Self> {
         let name = try_opt!(req.form_value("name"));
         let description = try_opt!(req.form_value("description"));
-        let private = req.form_value("private");
+        let visibility = try_opt!(Visibility::new(&try_opt!(req.form_value("visibility"))));
 
         if name.is_empty() {
             None
@@ -66,7 +386,7 @@ impl Repo {
                 name: name,
                 description: description,
                 owner: owner,
-                private: private == Some(String::from("on")),
+                visibility: visibility,
             })
         }
     }
@@ -85,6 +405,79 @@ pub struct Tag {
     pub name: String,
 }
 
+/// A branch or tag on the refs page.
+pub struct RefInfo {
+    pub name: String,
+    pub tag: bool,
+    pub is_default: bool,
+    /// The last commit
+    pub commit: Commit,
+    /// Commits ahead of and behind the default branch, if this isn't it
+    pub ahead_behind: Option<(usize, usize)>,
+}
+
+/// A parent of a commit, merges have several to diff against.
+pub struct CommitParent {
+    /// Starts at 1 like git's `commit^n`
+    pub number: usize,
+    pub id: String,
+    pub short_id: String,
+    pub selected: bool,
+}
+
+/// The changes to one file in a commit.
+pub struct DiffFile {
+    pub old_path: String,
+    pub new_path: String,
+    /// added, deleted, modified, renamed, copied or typechange
+    pub status: &'static str,
+    pub additions: usize,
+    pub deletions: usize,
+    pub binary: bool,
+    /// Whether `additions` and `deletions` were counted, they aren't for binary
+    /// files and once the line budget ran out
+    pub counted: bool,
+    /// Large diffs start out collapsed
+    pub collapsed: bool,
+    /// Whether the diff is too large to show, on its own or because the diffs
+    /// before it already used up the budget
+    pub too_large: bool,
+    /// Empty if the diff is binary or too large to show
+    pub lines: Vec<DiffLine>,
+}
+
+impl DiffFile {
+    pub fn renamed(&self) -> bool {
+        self.old_path != self.new_path
+    }
+}
+
+pub struct DiffLine {
+    /// add, del, ctx or hunk, used as the css class of the line
+    pub kind: &'static str,
+    pub old_line: Option<u32>,
+    pub new_line: Option<u32>,
+    pub content: String,
+}
+
+/// A branch or tag pointing at a commit.
+#[derive(Clone)]
+pub struct RefBadge {
+    pub name: String,
+    pub tag: bool,
+}
+
+/// Consecutive lines of a file last changed by the same commit.
+pub struct BlameGroup {
+    pub commit: Commit,
+    /// The file's path in `commit`
+    pub path: String,
+    /// The first parent of `commit`, for blaming the lines before it changed
+    pub parent: Option<String>,
+    pub lines: Vec<(usize, String)>,
+}
+
+#[derive(Clone)]
 pub struct Commit {
     pub id: String,
     pub short_id: String,
@@ -92,6 +485,8 @@ pub struct Commit {
     pub time: String,
     pub short_message: String,
     pub message: String,
+    /// Filled in by the views that show them
+    pub refs: Vec<RefBadge>,
 }
 
 impl Commit {
@@ -111,14 +506,17 @@ impl Commit {
             author: author_name,
             time: time,
             short_message: short_message,
-            message: message
+            message: message,
+            refs: Vec::new(),
         })
     }
 }
 
 #[derive(Explode)]
 pub enum RepoSrc {
-    File{ file: Vec<(usize, String)>, size: String },
+    /// `highlighted` has the lines as HTML, it's empty if the file wasn't
+    /// highlighted
+    File{ file: Vec<(usize, String)>, size: String, highlighted: Vec<(usize, String)> },
     Dir { items: Vec<RepoItem>, readme: Option<String> },
     Error,
 }
@@ -168,3 +566,172 @@ impl NewSshKey {
         }
     }
 }
+
+pub struct SigningKey {
+    pub id: i32,
+    pub owner: i32,
+    pub name: String,
+    pub kind: String,
+    pub fingerprint: String,
+    pub content: String,
+}
+
+pub struct NewSigningKey {
+    pub owner: i32,
+    pub name: String,
+    pub kind: String,
+    pub fingerprint: String,
+    pub content: String,
+}
+
+impl NewSigningKey {
+    pub fn new(req: &mut Request, owner: i32) -> Option<Self> {
+        let (name, key) = form_values!(req, "name", "signing_key");
+        let key = key.trim().to_string();
+
+        let (kind, fingerprint) = if key.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+            let fingerprint = try_opt!(::git::signing::gpg_fingerprint(&key).ok());
+            ("gpg", try_opt!(fingerprint))
+        } else {
+            ("ssh", try_opt!(NewSshKey::fingerprint(&key)))
+        };
+
+        Some(NewSigningKey {
+            owner: owner,
+            name: name,
+            kind: kind.to_string(),
+            fingerprint: fingerprint,
+            content: key,
+        })
+    }
+}
+
+pub struct Invite {
+    pub id: i32,
+    pub code: String,
+    pub created_by: String,
+    pub expires: NaiveDateTime,
+    pub used_by: Option<String>,
+}
+
+pub struct NewInvite {
+    pub code: String,
+    pub created_by: i32,
+    pub expires: NaiveDateTime,
+}
+
+impl NewInvite {
+    /// Creates a single use invitation that expires after the number of days
+    /// given in the form.
+    pub fn new(req: &mut Request, created_by: i32) -> Option<Self> {
+        let days = try_opt!(req.form_value("days").and_then(|d| d.parse::<i64>().ok()));
+        if days < 1 || days > 30 {
+            return None;
+        }
+
+        let code = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        Some(NewInvite {
+            code: code,
+            created_by: created_by,
+            expires: Utc::now().naive_utc() + Duration::days(days),
+        })
+    }
+}
+
+pub struct AdminUser {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+    pub num_repos: i64,
+    pub is_admin: bool,
+    pub is_org: bool,
+    pub disabled: bool,
+    /// Whether the user logs in with the LDAP directory
+    pub ldap: bool,
+}
+
+pub struct AdminRepo {
+    pub id: i64,
+    pub owner: String,
+    pub name: String,
+    pub visibility: Visibility,
+}
+
+/// Failed authentication attempts for an account (`kind` "user") or an IP
+/// address (`kind` "ip").
+pub struct Throttle {
+    pub id: i32,
+    pub kind: String,
+    pub key: String,
+    pub failures: i64,
+    pub last_failure: NaiveDateTime,
+    pub locked_until: Option<NaiveDateTime>,
+}
+
+pub struct AuthEvent {
+    pub created: NaiveDateTime,
+    pub event: String,
+    pub username: String,
+    pub ip: String,
+    pub source: String,
+}
+
+/// The user a request was made by, loaded from their session.
+#[derive(Clone)]
+pub struct CurrentUser {
+    pub id: i32,
+    pub username: String,
+    pub is_admin: bool,
+}
+
+pub struct PendingLogin {
+    pub username: String,
+    pub expires: Instant,
+}
+
+/// A TOTP secret that was shown to the user but not confirmed yet.
+pub struct PendingTotp {
+    pub secret: String,
+    pub expires: Instant,
+}
+
+/// An OpenID Connect login waiting for the provider to redirect back.
+pub struct PendingOidc {
+    pub verifier: String,
+    pub nonce: String,
+    pub expires: Instant,
+}
+
+/// An OpenID Connect identity waiting to be linked to the local account with
+/// the same email, once the user enters that account's password.
+#[derive(Clone)]
+pub struct PendingOidcLink {
+    pub user: i32,
+    pub issuer: String,
+    pub subject: String,
+    pub expires: Instant,
+}
+
+/// The number of commits reachable from a commit, keyed by repo path and
+/// commit.
+pub struct CommitCounts {
+    pub counts: HashMap<(PathBuf, ::git2::Oid), usize>,
+    /// Commits waiting to be counted
+    pub queued: HashSet<(PathBuf, ::git2::Oid)>,
+    pub queue: SyncSender<(PathBuf, ::git2::Oid)>,
+}
+
+/// A login session as listed on the session management page. `id` is a hash
+/// of the session key so that the key itself is never stored.
+pub struct UserSession {
+    pub id: String,
+    pub created: NaiveDateTime,
+    pub last_seen: NaiveDateTime,
+    pub user_agent: String,
+    pub ip: String,
+    pub current: bool,
+}